@@ -0,0 +1,5 @@
+-- Existing tokens were short and stored in plain text, so everyone has to log in again
+delete from sessions;
+
+alter table sessions rename column token to token_hash;
+create unique index sessions_token_hash_idx on sessions (token_hash);
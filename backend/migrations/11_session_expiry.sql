@@ -0,0 +1,8 @@
+alter table sessions add column expires_at timestamptz not null default current_timestamp + interval '30 days';
+alter table sessions add column rotated_at timestamptz not null default current_timestamp;
+
+-- The token from before the last rotation, accepted briefly for requests that were already in flight
+alter table sessions add column previous_token_hash text;
+
+create index sessions_previous_token_hash_idx on sessions (previous_token_hash);
+create index sessions_expires_at_idx on sessions (expires_at);
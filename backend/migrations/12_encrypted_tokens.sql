@@ -0,0 +1,6 @@
+-- Tokens are encrypted with a random data key per user, which is itself encrypted with a key from
+-- the environment. Rows without a key id still hold plain text until `rotate-token-key` is run.
+alter table users alter column access_token type bytea using convert_to(access_token, 'UTF8');
+alter table users alter column refresh_token type bytea using convert_to(refresh_token, 'UTF8');
+alter table users add column data_key bytea;
+alter table users add column key_id text;
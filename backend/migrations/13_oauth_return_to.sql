@@ -0,0 +1 @@
+alter table oauth_states add column return_to text;
@@ -0,0 +1,5 @@
+-- Cached from Spotify's /me, refreshed when it gets stale
+alter table users add column display_name text;
+alter table users add column avatar_url text;
+alter table users add column product text;
+alter table users add column profile_updated_at timestamptz;
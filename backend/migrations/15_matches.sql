@@ -0,0 +1,12 @@
+-- Every vote, so stats and history can be shown per user
+create table matches (
+    id serial primary key,
+    user_id integer not null references users(id) on delete cascade,
+    playlist_id text not null,
+    song_a text not null,
+    song_b text not null,
+    winner text not null,
+    created_at timestamptz not null default current_timestamp
+);
+
+create index matches_user_id_idx on matches (user_id);
@@ -0,0 +1,8 @@
+-- Deleting a user removes everything that belongs to them
+alter table sessions
+    drop constraint sessions_user_id_fkey,
+    add constraint sessions_user_id_fkey foreign key (user_id) references users(id) on delete cascade;
+
+alter table exported_playlists
+    drop constraint exported_playlists_user_id_fkey,
+    add constraint exported_playlists_user_id_fkey foreign key (user_id) references users(id) on delete cascade;
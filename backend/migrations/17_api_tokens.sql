@@ -0,0 +1,13 @@
+-- Personal access tokens for scripts, only the hash is stored like for sessions
+create table api_tokens (
+    id serial primary key,
+    user_id integer not null references users(id) on delete cascade,
+    name text not null,
+    token_hash text not null unique,
+    scopes text[] not null,
+    created_at timestamptz not null default current_timestamp,
+    last_used_at timestamptz,
+    expires_at timestamptz
+);
+
+create index api_tokens_user_id_idx on api_tokens (user_id);
@@ -0,0 +1,43 @@
+-- Links that let people without an account vote on someone's collection
+create table share_links (
+    id serial primary key,
+    user_id integer not null references users(id) on delete cascade,
+    playlist_id text not null,
+    token_hash text not null unique,
+    -- How far guest votes move the ratings, 0 only records them
+    vote_weight float8 not null default 1,
+    created_at timestamptz not null default current_timestamp,
+    expires_at timestamptz,
+    revoked_at timestamptz
+);
+
+create index share_links_user_id_idx on share_links (user_id);
+
+create table guest_sessions (
+    id serial primary key,
+    share_link_id integer not null references share_links(id) on delete cascade,
+    token_hash text not null unique,
+    name text,
+    created_at timestamptz not null default current_timestamp,
+    expires_at timestamptz not null,
+    -- Requests in the current rate limit window
+    window_start timestamptz not null default current_timestamp,
+    window_requests integer not null default 0
+);
+
+create index guest_sessions_share_link_id_idx on guest_sessions (share_link_id);
+
+-- Kept apart from `matches` so owners can tell guest votes apart, and outlive the guest sessions
+create table guest_votes (
+    id serial primary key,
+    share_link_id integer not null references share_links(id) on delete cascade,
+    guest_session_id integer references guest_sessions(id) on delete set null,
+    playlist_id text not null,
+    song_a text not null,
+    song_b text not null,
+    winner text not null,
+    weight float8 not null,
+    created_at timestamptz not null default current_timestamp
+);
+
+create index guest_votes_share_link_id_idx on guest_votes (share_link_id);
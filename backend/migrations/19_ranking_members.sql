@@ -0,0 +1,12 @@
+-- Who can do what in a ranking session, i.e. a synced collection. Collections without any members
+-- are claimed by the next user who syncs them.
+create table ranking_members (
+    playlist_id text not null,
+    user_id integer not null references users(id) on delete cascade,
+    role text not null check (role in ('owner', 'editor', 'voter', 'viewer')),
+    invited_by integer references users(id) on delete set null,
+    created_at timestamptz not null default current_timestamp,
+    primary key (playlist_id, user_id)
+);
+
+create index ranking_members_user_id_idx on ranking_members (user_id);
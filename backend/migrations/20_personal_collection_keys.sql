@@ -0,0 +1,36 @@
+-- Liked songs and top tracks belong to one user, so their keys now carry the user id
+-- (`saved:<user_id>`, `top:<time_range>:<user_id>`). Existing rows go to the owner of the ranking,
+-- or else to whoever voted on it the most.
+create temporary table personal_keys as
+select distinct on (old_key)
+    old_key,
+    old_key || ':' || user_id as new_key
+from (
+    select playlist_id as old_key, user_id, 0 as priority, 0 as votes
+    from ranking_members
+    where role = 'owner'
+    union all
+    select playlist_id, user_id, 1, count(*)
+    from matches
+    group by playlist_id, user_id
+) candidates
+where old_key = 'saved' or old_key ~ '^top:[a-z_]+$'
+order by old_key, priority, votes desc;
+
+update songs set playlist_id = k.new_key from personal_keys k where songs.playlist_id = k.old_key;
+update playlists set playlist_id = k.new_key from personal_keys k where playlists.playlist_id = k.old_key;
+update matches set playlist_id = k.new_key from personal_keys k where matches.playlist_id = k.old_key;
+update exported_playlists set playlist_id = k.new_key from personal_keys k where exported_playlists.playlist_id = k.old_key;
+update share_links set playlist_id = k.new_key from personal_keys k where share_links.playlist_id = k.old_key;
+update guest_votes set playlist_id = k.new_key from personal_keys k where guest_votes.playlist_id = k.old_key;
+update ranking_members set playlist_id = k.new_key from personal_keys k where ranking_members.playlist_id = k.old_key;
+
+-- Nobody synced or voted on these, so there is no one to attribute them to
+delete from songs where playlist_id = 'saved' or playlist_id ~ '^top:[a-z_]+$';
+delete from playlists where playlist_id = 'saved' or playlist_id ~ '^top:[a-z_]+$';
+delete from exported_playlists where playlist_id = 'saved' or playlist_id ~ '^top:[a-z_]+$';
+delete from share_links where playlist_id = 'saved' or playlist_id ~ '^top:[a-z_]+$';
+delete from guest_votes where playlist_id = 'saved' or playlist_id ~ '^top:[a-z_]+$';
+delete from ranking_members where playlist_id = 'saved' or playlist_id ~ '^top:[a-z_]+$';
+
+drop table personal_keys;
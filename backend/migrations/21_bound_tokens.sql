@@ -0,0 +1,3 @@
+-- Tokens are now encrypted with their user and column as associated data, so they can't be moved
+-- to another row. The server seals rows that aren't bound yet, including plain text ones, at startup.
+alter table users add column tokens_bound boolean not null default false;
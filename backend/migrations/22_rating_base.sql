@@ -0,0 +1,16 @@
+-- Ratings are rebuilt from a base rating and the recorded votes when the weight of a share link
+-- changes, so past guest votes count with the new weight. Not every vote before this was recorded,
+-- so existing ratings become the base and only later votes are replayed.
+alter table songs
+    add column base_rating float8 not null default 1500,
+    add column base_deviation float8 not null default 350,
+    add column base_volatility float8 not null default 0.06,
+    add column base_matches integer not null default 0,
+    -- Votes up to this are already part of the base, for new songs that is when they were added
+    add column rated_since timestamptz not null default current_timestamp;
+
+update songs set
+    base_rating = rating,
+    base_deviation = deviation,
+    base_volatility = volatility,
+    base_matches = total_matches;
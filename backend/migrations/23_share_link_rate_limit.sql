@@ -0,0 +1,4 @@
+-- All guests of a link use the same owner's Spotify quota, so each link also gets a request budget
+alter table share_links
+    add column window_start timestamptz not null default current_timestamp,
+    add column window_requests integer not null default 0;
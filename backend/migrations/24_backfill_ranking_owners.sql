@@ -0,0 +1,28 @@
+-- Rankings synced before roles existed have no members, so they were forbidden to everyone until
+-- someone claimed them again. Personal collections go to their user, other rankings to whoever
+-- voted on them the most or else shared them, and everyone else who voted keeps voting.
+with owners as (
+    insert into ranking_members (playlist_id, user_id, role)
+    select distinct on (playlist_id) playlist_id, user_id, 'owner'
+    from (
+        select playlist_id, substring(playlist_id from ':(\d+)$')::integer as user_id, 0 as priority, 0 as votes
+        from playlists
+        where playlist_id ~ '^(saved|top:[a-z_]+):\d+$'
+        union all
+        select playlist_id, user_id, 1, count(*)
+        from matches
+        group by playlist_id, user_id
+        union all
+        select playlist_id, user_id, 2, count(*)
+        from share_links
+        group by playlist_id, user_id
+    ) candidates
+    where user_id in (select id from users)
+      and not exists (select 1 from ranking_members m where m.playlist_id = candidates.playlist_id)
+    order by playlist_id, priority, votes desc
+    returning playlist_id, user_id
+)
+insert into ranking_members (playlist_id, user_id, role)
+select distinct m.playlist_id, m.user_id, 'voter'
+from matches m
+join owners o on o.playlist_id = m.playlist_id and o.user_id <> m.user_id;
@@ -0,0 +1,12 @@
+-- Links only keep working while their creator can still share the ranking, otherwise guests would
+-- keep using the Spotify account of someone who no longer has a say in it
+update share_links l set revoked_at = current_timestamp
+where l.revoked_at is null
+  and not exists (
+      select 1 from ranking_members m
+      where m.playlist_id = l.playlist_id and m.user_id = l.user_id and m.role in ('editor', 'owner')
+  );
+
+delete from guest_sessions g
+using share_links l
+where l.id = g.share_link_id and l.revoked_at is not null;
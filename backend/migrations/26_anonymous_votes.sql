@@ -0,0 +1,12 @@
+-- Votes are part of the ratings everyone else in a ranking sees, so deleting an account only
+-- removes who cast them. Ratings are replayed from these, a missing vote would change them.
+alter table matches alter column user_id drop not null;
+alter table matches drop constraint matches_user_id_fkey;
+alter table matches add constraint matches_user_id_fkey
+    foreign key (user_id) references users(id) on delete set null;
+
+-- Links keep their guest votes for the same reason, they are revoked before the account is deleted
+alter table share_links alter column user_id drop not null;
+alter table share_links drop constraint share_links_user_id_fkey;
+alter table share_links add constraint share_links_user_id_fkey
+    foreign key (user_id) references users(id) on delete set null;
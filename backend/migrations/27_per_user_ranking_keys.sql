@@ -0,0 +1,30 @@
+-- Playlists, albums and artists were ranked in one session per source, owned by whoever synced it
+-- first, which locked everyone else out. Every user now has a session of their own, keyed
+-- `<id>:<user_id>`, `album:<id>:<user_id>` and `artist:<id>:<user_id>`. Existing sessions go to
+-- their longest standing owner, and keep their other members.
+create temporary table shared_keys as
+select distinct on (playlist_id)
+    playlist_id as old_key,
+    playlist_id || ':' || user_id as new_key
+from ranking_members
+where role = 'owner' and (playlist_id !~ ':' or playlist_id ~ '^(album|artist):[^:]+$')
+order by playlist_id, created_at, user_id;
+
+update songs set playlist_id = k.new_key from shared_keys k where songs.playlist_id = k.old_key;
+update playlists set playlist_id = k.new_key from shared_keys k where playlists.playlist_id = k.old_key;
+update matches set playlist_id = k.new_key from shared_keys k where matches.playlist_id = k.old_key;
+update exported_playlists set playlist_id = k.new_key from shared_keys k where exported_playlists.playlist_id = k.old_key;
+update share_links set playlist_id = k.new_key from shared_keys k where share_links.playlist_id = k.old_key;
+update guest_votes set playlist_id = k.new_key from shared_keys k where guest_votes.playlist_id = k.old_key;
+update ranking_members set playlist_id = k.new_key from shared_keys k where ranking_members.playlist_id = k.old_key;
+
+-- Sessions without an owner can't be opened by anyone anymore
+delete from songs where playlist_id !~ ':' or playlist_id ~ '^(album|artist):[^:]+$';
+delete from playlists where playlist_id !~ ':' or playlist_id ~ '^(album|artist):[^:]+$';
+delete from matches where playlist_id !~ ':' or playlist_id ~ '^(album|artist):[^:]+$';
+delete from exported_playlists where playlist_id !~ ':' or playlist_id ~ '^(album|artist):[^:]+$';
+delete from guest_votes where playlist_id !~ ':' or playlist_id ~ '^(album|artist):[^:]+$';
+delete from share_links where playlist_id !~ ':' or playlist_id ~ '^(album|artist):[^:]+$';
+delete from ranking_members where playlist_id !~ ':' or playlist_id ~ '^(album|artist):[^:]+$';
+
+drop table shared_keys;
@@ -0,0 +1,6 @@
+create table if not exists playlists (
+    id serial primary key,
+    playlist_id text not null unique,
+    snapshot_id text not null,
+    last_synced timestamptz not null default current_timestamp
+);
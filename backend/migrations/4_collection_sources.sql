@@ -0,0 +1,2 @@
+-- Only playlists have a snapshot id, other collection sources are diffed on every sync
+alter table playlists alter column snapshot_id drop not null;
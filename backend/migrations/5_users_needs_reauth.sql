@@ -0,0 +1,2 @@
+-- Set when Spotify rejects the refresh token, e.g. after the user revoked access to the app
+alter table users add column if not exists needs_reauth boolean not null default false;
@@ -0,0 +1,7 @@
+create table if not exists exported_playlists (
+    id serial primary key,
+    user_id integer not null references users(id),
+    playlist_id text not null,
+    target_playlist_id text not null,
+    unique (user_id, playlist_id)
+);
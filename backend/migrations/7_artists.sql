@@ -0,0 +1,14 @@
+create table if not exists artists (
+    artist_id text primary key,
+    name text not null,
+    genres text[] not null default '{}',
+    image_url text,
+    popularity integer not null default 0,
+    updated_at timestamptz not null default current_timestamp
+);
+
+create table if not exists song_artists (
+    song_id text not null,
+    artist_id text not null references artists(artist_id),
+    primary key (song_id, artist_id)
+);
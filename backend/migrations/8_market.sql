@@ -0,0 +1,5 @@
+alter table users add column if not exists country text;
+
+-- Availability in the market of the user who last synced the collection, null if unknown
+alter table songs add column if not exists is_playable boolean;
+alter table songs add column if not exists restriction text;
@@ -0,0 +1,6 @@
+-- Pending logins, the state is also kept in a cookie to tie the callback to the browser that started it
+create table oauth_states (
+    state text primary key,
+    pkce_verifier text not null,
+    created_at timestamptz not null default current_timestamp
+);
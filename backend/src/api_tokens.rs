@@ -0,0 +1,123 @@
+use axum::{
+    extract::{FromRequestParts, State},
+    http::header,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    error::ApiError,
+    session::{self, User},
+};
+
+// Makes tokens recognisable, e.g. for secret scanners
+pub const TOKEN_PREFIX: &str = "srk_";
+
+/// What a personal access token is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Read playlists and leaderboards
+    Read,
+    /// Get matches and vote on them
+    Vote,
+    /// Export rankings and account data
+    Export,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Vote => "vote",
+            Scope::Export => "export",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "vote" => Some(Scope::Vote),
+            "export" => Some(Scope::Export),
+            _ => None,
+        }
+    }
+}
+
+/// A new personal access token, only its hash should be stored
+pub fn generate_token() -> String {
+    format!("{}{}", TOKEN_PREFIX, session::generate_token())
+}
+
+/// The user behind an `Authorization: Bearer` personal access token, or the `session_token`
+/// cookie if there is no such header. Sessions can do anything, tokens only what their scopes allow.
+pub struct ApiUser {
+    pub id: i32,
+    pub spotify_id: String,
+    scopes: Option<Vec<Scope>>,
+}
+
+impl ApiUser {
+    pub fn require(&self, scope: Scope) -> Result<(), ApiError> {
+        match &self.scopes {
+            Some(scopes) if !scopes.contains(&scope) => {
+                Err(ApiError::Forbidden("Token is missing the required scope"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for ApiUser
+where
+    S: Send + Sync,
+    AppState: axum::extract::FromRef<S>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(authorization) = parts.headers.get(header::AUTHORIZATION) else {
+            let user = User::from_request_parts(parts, state).await?;
+
+            return Ok(ApiUser {
+                id: user.id,
+                spotify_id: user.spotify_id,
+                scopes: None,
+            });
+        };
+
+        let token_hash = authorization
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| session::hash_token(token.trim()))
+            .ok_or(ApiError::Unauthorized("Invalid authorization header"))?;
+
+        let State(app_state): State<AppState> =
+            State::from_request_parts(parts, state).await.unwrap();
+
+        let row = sqlx::query!(
+            "UPDATE api_tokens t SET last_used_at = NOW()
+             FROM users u
+             WHERE u.id = t.user_id AND t.token_hash = $1 AND (t.expires_at IS NULL OR t.expires_at > NOW())
+             RETURNING u.id, u.spotify_id, u.needs_reauth, t.scopes",
+            token_hash
+        )
+        .fetch_optional(&app_state.pool)
+        .await?
+        .ok_or(ApiError::Unauthorized("Invalid token"))?;
+
+        if row.needs_reauth {
+            return Err(ApiError::ReauthRequired);
+        }
+
+        Ok(ApiUser {
+            id: row.id,
+            spotify_id: row.spotify_id,
+            scopes: Some(row.scopes.iter().filter_map(|s| Scope::parse(s)).collect()),
+        })
+    }
+}
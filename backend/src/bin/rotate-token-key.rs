@@ -0,0 +1,73 @@
+//! Re-encrypts the stored Spotify tokens with the current `TOKEN_ENCRYPTION_KEY`, and encrypts
+//! any that are still plain text or not bound to their row. To rotate, move the old key to `TOKEN_ENCRYPTION_PREVIOUS_KEYS`,
+//! set the new one, restart the server and run this. The old key can be dropped afterwards.
+
+use spotify_rankings::crypto::{self, Keyring};
+use sqlx::postgres::PgPoolOptions;
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt().init();
+    dotenvy::dotenv().expect("Failed to load .env file");
+
+    let pool = PgPoolOptions::new()
+        .connect(&dotenvy::var("DATABASE_URL").expect("Environment variable DATABASE_URL not set"))
+        .await
+        .expect("Failed to connect to the database");
+
+    let keyring = Keyring::from_env().expect("Failed to load the token encryption keys");
+
+    // Tokens that aren't bound to their row yet are sealed with the current key right away
+    let sealed = crypto::seal_unbound_tokens(&pool, &keyring)
+        .await
+        .expect("Failed to encrypt the stored tokens");
+
+    let users = sqlx::query!(
+        "SELECT id, spotify_id, data_key, key_id FROM users WHERE key_id IS DISTINCT FROM $1",
+        keyring.current_id()
+    )
+    .fetch_all(&pool)
+    .await
+    .expect("Failed to fetch users");
+
+    let mut failed = 0;
+
+    for user in &users {
+        // Only the data key has to be wrapped again. Rows that were written in the meantime
+        // already use the current key, so they're skipped.
+        let result = match (&user.key_id, &user.data_key) {
+            (Some(key_id), Some(data_key)) => {
+                match keyring.rewrap(&user.spotify_id, key_id, data_key) {
+                    Ok(data_key) => sqlx::query!(
+                        "UPDATE users SET data_key = $1, key_id = $2 WHERE id = $3 AND key_id = $4",
+                        data_key,
+                        keyring.current_id(),
+                        user.id,
+                        key_id
+                    )
+                    .execute(&pool)
+                    .await
+                    .map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+            _ => Err("tokens are not encrypted".to_string()),
+        };
+
+        if let Err(e) = result {
+            tracing::error!("Failed to re-encrypt tokens of user {}: {}", user.id, e);
+            failed += 1;
+        }
+    }
+
+    tracing::info!(
+        "Encrypted tokens of {} users, re-encrypted {}, {} failed",
+        sealed,
+        users.len() - failed,
+        failed
+    );
+
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,430 @@
+use std::{collections::HashMap, sync::Arc};
+
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use sha2::{Digest, Sha256};
+
+use crate::error::ApiError;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CryptoError {
+    #[error("Invalid encryption key: {0}")]
+    InvalidKey(&'static str),
+    #[error("No encryption key with id {0}")]
+    UnknownKey(String),
+    #[error("Failed to decrypt stored tokens")]
+    Decrypt,
+    #[error("Stored tokens are not encrypted")]
+    NotEncrypted,
+}
+
+/// The Spotify tokens of a user, encrypted with a data key that is only stored wrapped
+pub struct SealedTokens {
+    pub access_token: Vec<u8>,
+    pub refresh_token: Vec<u8>,
+    pub data_key: Vec<u8>,
+    pub key_id: String,
+}
+
+/// Key encryption keys for the tokens stored in `users`. New data keys are always wrapped with
+/// the current key, previous keys are only kept around to read rows that haven't been rotated.
+///
+/// Every value is encrypted with the Spotify id of its user and its column as associated data, so
+/// it can't be decrypted after being copied to another row or column. The Spotify id is used
+/// because the row id isn't known yet when a new user's tokens are sealed.
+#[derive(Clone)]
+pub struct Keyring {
+    current_id: String,
+    keys: Arc<HashMap<String, Aes256Gcm>>,
+}
+
+impl Keyring {
+    /// Keys are 32 bytes, hex encoded
+    pub fn new(current: &str, previous: &[&str]) -> Result<Self, CryptoError> {
+        let mut keys = HashMap::new();
+        let mut current_id = String::new();
+
+        for (idx, key) in std::iter::once(current)
+            .chain(previous.iter().copied())
+            .enumerate()
+        {
+            let bytes = hex::decode(key.trim()).map_err(|_| CryptoError::InvalidKey("not hex"))?;
+            if bytes.len() != KEY_LEN {
+                return Err(CryptoError::InvalidKey("must be 32 bytes"));
+            }
+
+            // Identifies the key without revealing it
+            let id = hex::encode(&Sha256::digest(&bytes)[..8]);
+            if idx == 0 {
+                current_id = id.clone();
+            }
+
+            keys.insert(id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)));
+        }
+
+        Ok(Self {
+            current_id,
+            keys: Arc::new(keys),
+        })
+    }
+
+    /// Reads `TOKEN_ENCRYPTION_KEY` and the comma separated `TOKEN_ENCRYPTION_PREVIOUS_KEYS`
+    pub fn from_env() -> Result<Self, CryptoError> {
+        let current = dotenvy::var("TOKEN_ENCRYPTION_KEY")
+            .map_err(|_| CryptoError::InvalidKey("TOKEN_ENCRYPTION_KEY not set"))?;
+        let previous = dotenvy::var("TOKEN_ENCRYPTION_PREVIOUS_KEYS").unwrap_or_default();
+        let previous: Vec<&str> = previous.split(',').filter(|key| !key.is_empty()).collect();
+
+        Self::new(&current, &previous)
+    }
+
+    pub fn current_id(&self) -> &str {
+        &self.current_id
+    }
+
+    /// Encrypts both tokens of a user with a new data key
+    pub fn seal(&self, spotify_id: &str, access_token: &str, refresh_token: &str) -> SealedTokens {
+        let data_key = rand::random::<[u8; KEY_LEN]>();
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key));
+
+        SealedTokens {
+            access_token: encrypt(
+                &cipher,
+                access_token.as_bytes(),
+                &aad(spotify_id, "access_token"),
+            ),
+            refresh_token: encrypt(
+                &cipher,
+                refresh_token.as_bytes(),
+                &aad(spotify_id, "refresh_token"),
+            ),
+            data_key: encrypt(
+                &self.keys[&self.current_id],
+                &data_key,
+                &aad(spotify_id, "data_key"),
+            ),
+            key_id: self.current_id.clone(),
+        }
+    }
+
+    /// Decrypts the access and refresh token of a user
+    pub fn open(
+        &self,
+        spotify_id: &str,
+        key_id: Option<&str>,
+        data_key: Option<&[u8]>,
+        access_token: &[u8],
+        refresh_token: &[u8],
+    ) -> Result<(String, String), CryptoError> {
+        let (Some(key_id), Some(data_key)) = (key_id, data_key) else {
+            return Err(CryptoError::NotEncrypted);
+        };
+
+        let data_key = decrypt(self.key(key_id)?, data_key, &aad(spotify_id, "data_key"))?;
+        let cipher = data_cipher(&data_key)?;
+
+        Ok((
+            plain_text(&decrypt(
+                &cipher,
+                access_token,
+                &aad(spotify_id, "access_token"),
+            )?)?,
+            plain_text(&decrypt(
+                &cipher,
+                refresh_token,
+                &aad(spotify_id, "refresh_token"),
+            )?)?,
+        ))
+    }
+
+    /// Wraps an existing data key with the current key, the tokens themselves don't change
+    pub fn rewrap(
+        &self,
+        spotify_id: &str,
+        key_id: &str,
+        data_key: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let aad = aad(spotify_id, "data_key");
+        let data_key = decrypt(self.key(key_id)?, data_key, &aad)?;
+        Ok(encrypt(&self.keys[&self.current_id], &data_key, &aad))
+    }
+
+    // Reads tokens written before they were bound to their row, which are plain text if the row
+    // has no key id
+    fn open_unbound(
+        &self,
+        key_id: Option<&str>,
+        data_key: Option<&[u8]>,
+        access_token: &[u8],
+        refresh_token: &[u8],
+    ) -> Result<(String, String), CryptoError> {
+        let (Some(key_id), Some(data_key)) = (key_id, data_key) else {
+            return Ok((plain_text(access_token)?, plain_text(refresh_token)?));
+        };
+
+        let data_key = decrypt(self.key(key_id)?, data_key, &[])?;
+        let cipher = data_cipher(&data_key)?;
+
+        Ok((
+            plain_text(&decrypt(&cipher, access_token, &[])?)?,
+            plain_text(&decrypt(&cipher, refresh_token, &[])?)?,
+        ))
+    }
+
+    fn key(&self, key_id: &str) -> Result<&Aes256Gcm, CryptoError> {
+        self.keys
+            .get(key_id)
+            .ok_or_else(|| CryptoError::UnknownKey(key_id.to_string()))
+    }
+}
+
+/// Encrypts tokens that are still plain text, and re-encrypts those that aren't bound to their
+/// row yet. Run at startup so no request ever has to accept either, returns how many were sealed.
+pub async fn seal_unbound_tokens(pool: &sqlx::PgPool, keyring: &Keyring) -> Result<u64, ApiError> {
+    let users = sqlx::query!(
+        "SELECT id, spotify_id, access_token, refresh_token, data_key, key_id FROM users WHERE NOT tokens_bound"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut sealed_rows = 0;
+    for user in users {
+        let (access_token, refresh_token) = keyring.open_unbound(
+            user.key_id.as_deref(),
+            user.data_key.as_deref(),
+            &user.access_token,
+            &user.refresh_token,
+        )?;
+        let sealed = keyring.seal(&user.spotify_id, &access_token, &refresh_token);
+
+        // Rows written in the meantime are already bound, so they are left alone
+        let result = sqlx::query!(
+            "UPDATE users SET access_token = $1, refresh_token = $2, data_key = $3, key_id = $4, tokens_bound = TRUE
+             WHERE id = $5 AND NOT tokens_bound",
+            sealed.access_token,
+            sealed.refresh_token,
+            sealed.data_key,
+            sealed.key_id,
+            user.id
+        )
+        .execute(pool)
+        .await?;
+
+        sealed_rows += result.rows_affected();
+    }
+
+    Ok(sealed_rows)
+}
+
+// Binds a value to the row and column it is stored in
+fn aad(spotify_id: &str, column: &str) -> Vec<u8> {
+    format!("users/{}/{}", spotify_id, column).into_bytes()
+}
+
+fn data_cipher(data_key: &[u8]) -> Result<Aes256Gcm, CryptoError> {
+    if data_key.len() != KEY_LEN {
+        return Err(CryptoError::Decrypt);
+    }
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(data_key)))
+}
+
+// The nonce is stored in front of the ciphertext
+fn encrypt(cipher: &Aes256Gcm, plain_text: &[u8], aad: &[u8]) -> Vec<u8> {
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    let cipher_text = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plain_text,
+                aad,
+            },
+        )
+        .expect("Encrypting in memory can't fail");
+
+    [nonce.as_slice(), &cipher_text].concat()
+}
+
+fn decrypt(cipher: &Aes256Gcm, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if data.len() < NONCE_LEN {
+        return Err(CryptoError::Decrypt);
+    }
+    let (nonce, cipher_text) = data.split_at(NONCE_LEN);
+
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: cipher_text,
+                aad,
+            },
+        )
+        .map_err(|_| CryptoError::Decrypt)
+}
+
+fn plain_text(bytes: &[u8]) -> Result<String, CryptoError> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| CryptoError::Decrypt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD_KEY: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+    const NEW_KEY: &str = "0000000000000000000000000000000000000000000000000000000000000002";
+
+    fn open(
+        keyring: &Keyring,
+        spotify_id: &str,
+        sealed: &SealedTokens,
+    ) -> Result<(String, String), CryptoError> {
+        keyring.open(
+            spotify_id,
+            Some(&sealed.key_id),
+            Some(&sealed.data_key),
+            &sealed.access_token,
+            &sealed.refresh_token,
+        )
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let keyring = Keyring::new(NEW_KEY, &[]).unwrap();
+        let sealed = keyring.seal("alice", "access", "refresh");
+
+        assert_eq!(sealed.key_id, keyring.current_id());
+        assert_ne!(sealed.access_token, b"access");
+        assert_eq!(
+            open(&keyring, "alice", &sealed).unwrap(),
+            ("access".to_string(), "refresh".to_string())
+        );
+    }
+
+    #[test]
+    fn open_fails_for_another_user() {
+        let keyring = Keyring::new(NEW_KEY, &[]).unwrap();
+        let sealed = keyring.seal("alice", "access", "refresh");
+
+        assert!(matches!(
+            open(&keyring, "mallory", &sealed),
+            Err(CryptoError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn open_fails_for_swapped_columns() {
+        let keyring = Keyring::new(NEW_KEY, &[]).unwrap();
+        let sealed = keyring.seal("alice", "access", "refresh");
+
+        let result = keyring.open(
+            "alice",
+            Some(&sealed.key_id),
+            Some(&sealed.data_key),
+            &sealed.refresh_token,
+            &sealed.access_token,
+        );
+        assert!(matches!(result, Err(CryptoError::Decrypt)));
+    }
+
+    #[test]
+    fn open_requires_encrypted_tokens() {
+        let keyring = Keyring::new(NEW_KEY, &[]).unwrap();
+
+        assert!(matches!(
+            keyring.open("alice", None, None, b"access", b"refresh"),
+            Err(CryptoError::NotEncrypted)
+        ));
+    }
+
+    #[test]
+    fn rewrap_under_new_key() {
+        let old = Keyring::new(OLD_KEY, &[]).unwrap();
+        let sealed = old.seal("alice", "access", "refresh");
+
+        let rotating = Keyring::new(NEW_KEY, &[OLD_KEY]).unwrap();
+        let data_key = rotating
+            .rewrap("alice", &sealed.key_id, &sealed.data_key)
+            .unwrap();
+
+        // Once rewrapped the old key is no longer needed
+        let new = Keyring::new(NEW_KEY, &[]).unwrap();
+        let rotated = SealedTokens {
+            data_key,
+            key_id: new.current_id().to_string(),
+            ..sealed
+        };
+        assert_eq!(
+            open(&new, "alice", &rotated).unwrap(),
+            ("access".to_string(), "refresh".to_string())
+        );
+        assert!(matches!(
+            open(&old, "alice", &rotated),
+            Err(CryptoError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn rewrap_fails_for_another_user() {
+        let keyring = Keyring::new(NEW_KEY, &[OLD_KEY]).unwrap();
+        let sealed = keyring.seal("alice", "access", "refresh");
+
+        assert!(matches!(
+            keyring.rewrap("mallory", &sealed.key_id, &sealed.data_key),
+            Err(CryptoError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn seal_legacy_plain_text_tokens() {
+        let keyring = Keyring::new(NEW_KEY, &[]).unwrap();
+        let tokens = keyring
+            .open_unbound(None, None, b"access", b"refresh")
+            .unwrap();
+        assert_eq!(tokens, ("access".to_string(), "refresh".to_string()));
+
+        let sealed = keyring.seal("alice", &tokens.0, &tokens.1);
+        assert_eq!(open(&keyring, "alice", &sealed).unwrap(), tokens);
+    }
+
+    #[test]
+    fn seal_legacy_unbound_tokens() {
+        // Encrypted the way rows were before the associated data was added
+        let old = Keyring::new(OLD_KEY, &[]).unwrap();
+        let data_key = rand::random::<[u8; KEY_LEN]>();
+        let cipher = data_cipher(&data_key).unwrap();
+        let access_token = encrypt(&cipher, b"access", &[]);
+        let refresh_token = encrypt(&cipher, b"refresh", &[]);
+        let wrapped = encrypt(&old.keys[old.current_id()], &data_key, &[]);
+
+        // Unbound rows can't be read as bound ones
+        assert!(
+            old.open(
+                "alice",
+                Some(old.current_id()),
+                Some(&wrapped),
+                &access_token,
+                &refresh_token
+            )
+            .is_err()
+        );
+
+        let keyring = Keyring::new(NEW_KEY, &[OLD_KEY]).unwrap();
+        let tokens = keyring
+            .open_unbound(
+                Some(old.current_id()),
+                Some(&wrapped),
+                &access_token,
+                &refresh_token,
+            )
+            .unwrap();
+        assert_eq!(tokens, ("access".to_string(), "refresh".to_string()));
+
+        let sealed = keyring.seal("alice", &tokens.0, &tokens.1);
+        assert_eq!(sealed.key_id, keyring.current_id());
+        assert_eq!(open(&keyring, "alice", &sealed).unwrap(), tokens);
+    }
+}
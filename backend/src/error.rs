@@ -1,5 +1,104 @@
+use axum::{
+    Json,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+
+use crate::{crypto::CryptoError, spotify::SpotifyError};
+
 #[derive(Debug, thiserror::Error)]
 pub enum ApiError {
     #[error("Database error: {0}")]
     DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    SpotifyError(#[from] SpotifyError),
+    #[error("Encryption error: {0}")]
+    CryptoError(#[from] CryptoError),
+    #[error("{0}")]
+    BadRequest(&'static str),
+    #[error("{0}")]
+    Unauthorized(&'static str),
+    #[error("Spotify access has been revoked, please log in again")]
+    ReauthRequired,
+    #[error("{0}")]
+    Forbidden(&'static str),
+    #[error("{0}")]
+    NotFound(&'static str),
+    #[error("{0}")]
+    Conflict(&'static str),
+    #[error("Too many requests, try again in {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::SpotifyError(err) => err.status(),
+            ApiError::CryptoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) | ApiError::ReauthRequired => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// Machine readable error code for the frontend to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::DatabaseError(_) => "database_error",
+            ApiError::SpotifyError(err) => err.code(),
+            ApiError::CryptoError(_) => "encryption_error",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::ReauthRequired => "reauth_required",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::RateLimited { .. } => "rate_limited",
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+
+        if status.is_server_error() {
+            tracing::error!("{}", self);
+        }
+
+        // Don't leak database or key internals to the client
+        let message = match &self {
+            ApiError::DatabaseError(_) | ApiError::CryptoError(_) => {
+                "Internal server error".to_string()
+            }
+            err => err.to_string(),
+        };
+
+        let body = Json(json!({
+            "error": {
+                "status": status.as_u16(),
+                "code": self.code(),
+                "message": message,
+            }
+        }));
+
+        let mut response = (status, body).into_response();
+
+        if let ApiError::SpotifyError(SpotifyError::RateLimited {
+            retry_after: Some(retry_after),
+        })
+        | ApiError::RateLimited { retry_after } = self
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+        }
+
+        response
+    }
 }
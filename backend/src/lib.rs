@@ -1,12 +1,15 @@
+pub mod api_tokens;
+pub mod crypto;
 pub mod error;
+pub mod roles;
 pub mod routes;
+pub mod session;
 pub mod spotify;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub client: reqwest::Client,
-    pub client_id: String,
-    pub client_secret: String,
-    pub redirect_uri: String,
+    pub spotify: spotify::SpotifyClient,
     pub pool: sqlx::Pool<sqlx::Postgres>,
+    /// Where the web app is served, the only place users are sent back to after logging in
+    pub frontend_url: reqwest::Url,
 }
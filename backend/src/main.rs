@@ -1,8 +1,17 @@
+use std::time::Duration;
+
 use axum::{
     Router,
     http::{Method, header},
+    middleware,
     routing::get,
 };
+use spotify_rankings::{
+    AppState,
+    crypto::{self, Keyring},
+    session,
+    spotify::SpotifyClient,
+};
 use sqlx::postgres::PgPoolOptions;
 use tower_http::cors::CorsLayer;
 
@@ -12,39 +21,95 @@ macro_rules! var {
     };
 }
 
+macro_rules! var_or {
+    ($key:expr, $default:expr) => {
+        dotenvy::var($key)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or($default)
+    };
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt().init();
     dotenvy::dotenv().expect("Failed to load .env file");
 
-    let client = reqwest::Client::new();
-    let client_id = var!("SPOTIFY_CLIENT_ID");
-    let client_secret = var!("SPOTIFY_CLIENT_SECRET");
-    let redirect_uri = var!("SPOTIFY_REDIRECT_URI");
-
     let pool = PgPoolOptions::new()
         .connect(&var!("DATABASE_URL"))
         .await
         .expect("Failed to connect to the database");
 
+    let keyring = Keyring::from_env().expect("Failed to load the token encryption keys");
+
+    // Tokens are only ever read bound to their row, so older ones have to be sealed again first
+    let sealed = crypto::seal_unbound_tokens(&pool, &keyring)
+        .await
+        .expect("Failed to encrypt the stored tokens");
+    if sealed > 0 {
+        tracing::info!("Encrypted the stored tokens of {} users", sealed);
+    }
+
+    let mut spotify = SpotifyClient::new(
+        pool.clone(),
+        keyring,
+        var!("SPOTIFY_CLIENT_ID"),
+        var!("SPOTIFY_CLIENT_SECRET"),
+        var!("SPOTIFY_REDIRECT_URI"),
+    );
+    spotify.page_concurrency = var_or!("SPOTIFY_PAGE_CONCURRENCY", 4);
+    spotify.refresh_margin = chrono::Duration::seconds(var_or!("SPOTIFY_REFRESH_MARGIN_SECS", 60));
+
+    // Expired sessions are already rejected, this only keeps the table from growing
+    let purge_interval = Duration::from_secs(var_or!("SESSION_PURGE_INTERVAL_SECS", 3600));
+    tokio::spawn({
+        let pool = pool.clone();
+        async move {
+            let mut interval = tokio::time::interval(purge_interval);
+            loop {
+                interval.tick().await;
+                match session::purge_expired(&pool).await {
+                    Ok(purged) => tracing::info!("Purged {} expired sessions", purged),
+                    Err(e) => tracing::error!("Failed to purge expired sessions: {}", e),
+                }
+            }
+        }
+    });
+
+    let frontend_url = reqwest::Url::parse(
+        &dotenvy::var("FRONTEND_URL").unwrap_or_else(|_| "http://localhost:5173".to_string()),
+    )
+    .expect("FRONTEND_URL is a valid URL");
+
     let cors = CorsLayer::new()
-        .allow_origin(axum::http::HeaderValue::from_static(
-            "http://localhost:5173",
-        ))
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_origin(
+            axum::http::HeaderValue::from_str(&frontend_url.origin().ascii_serialization())
+                .expect("FRONTEND_URL is a valid origin"),
+        )
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PATCH,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
         .allow_headers([header::CONTENT_TYPE])
         .allow_credentials(true);
 
+    let state = AppState {
+        spotify,
+        pool,
+        frontend_url,
+    };
+
     let app = Router::new()
         .route("/", get(|| async { "Hello, World!" }))
         .merge(spotify_rankings::routes::get_router())
-        .with_state(spotify_rankings::AppState {
-            client,
-            client_id,
-            client_secret,
-            redirect_uri,
-            pool,
-        })
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            session::rotate,
+        ))
+        .with_state(state)
         .layer(cors);
 
     tracing::info!("Starting server...");
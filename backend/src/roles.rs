@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{error::ApiError, spotify::CollectionSource};
+
+/// A user's role in a ranking session, each role can do everything the ones below it can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// See the leaderboards
+    Viewer,
+    /// Get matches and vote on them
+    Voter,
+    /// Sync, export, reorder and share the collection
+    Editor,
+    /// Manage the members
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Voter => "voter",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "viewer" => Some(Role::Viewer),
+            "voter" => Some(Role::Voter),
+            "editor" => Some(Role::Editor),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+}
+
+pub async fn role_of(
+    pool: &sqlx::PgPool,
+    playlist_id: &str,
+    user_id: i32,
+) -> Result<Option<Role>, ApiError> {
+    let role = sqlx::query_scalar!(
+        "SELECT role FROM ranking_members WHERE playlist_id = $1 AND user_id = $2",
+        playlist_id,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(role.as_deref().and_then(Role::parse))
+}
+
+/// Fails unless the user has at least `required` in the ranking session
+pub async fn require(
+    pool: &sqlx::PgPool,
+    playlist_id: &str,
+    user_id: i32,
+    required: Role,
+) -> Result<Role, ApiError> {
+    match role_of(pool, playlist_id, user_id).await? {
+        Some(role) if role >= required => Ok(role),
+        Some(_) => Err(ApiError::Forbidden("Your role doesn't allow this")),
+        None => Err(ApiError::Forbidden(
+            "You don't have access to this ranking, ask its owner for an invite",
+        )),
+    }
+}
+
+/// Like `require`, but passes with `None` if nobody has the session yet so it can be claimed
+pub async fn require_or_unclaimed(
+    pool: &sqlx::PgPool,
+    playlist_id: &str,
+    user_id: i32,
+    required: Role,
+) -> Result<Option<Role>, ApiError> {
+    let claimed = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM ranking_members WHERE playlist_id = $1) AS "claimed!""#,
+        playlist_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if !claimed {
+        return Ok(None);
+    }
+
+    require(pool, playlist_id, user_id, required)
+        .await
+        .map(Some)
+}
+
+/// Makes the user the owner if the session is their own and nobody has it yet, then requires
+/// `required`. Sessions of other users can only be joined through an invite.
+pub async fn claim(
+    pool: &sqlx::PgPool,
+    source: &CollectionSource,
+    user_id: i32,
+    required: Role,
+) -> Result<Role, ApiError> {
+    let playlist_id = source.key();
+    if source.user_id() != user_id {
+        return require(pool, &playlist_id, user_id, required).await;
+    }
+
+    // Only this user can start the session, so concurrent claims are just the same insert twice
+    sqlx::query!(
+        "INSERT INTO ranking_members (playlist_id, user_id, role)
+         SELECT $1, $2, $3
+         WHERE NOT EXISTS (SELECT 1 FROM ranking_members WHERE playlist_id = $1)
+         ON CONFLICT DO NOTHING",
+        playlist_id,
+        user_id,
+        Role::Owner.as_str()
+    )
+    .execute(pool)
+    .await?;
+
+    require(pool, &playlist_id, user_id, required).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn create_user(pool: &sqlx::PgPool, spotify_id: &str) -> i32 {
+        sqlx::query_scalar!(
+            "INSERT INTO users (spotify_id, access_token, refresh_token, expires_at) VALUES ($1, '', '', NOW()) RETURNING id",
+            spotify_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn every_user_claims_their_own_session(pool: sqlx::PgPool) {
+        let first = create_user(&pool, "first").await;
+        let second = create_user(&pool, "second").await;
+
+        let first_album = CollectionSource::from_key("album:abc", first).unwrap();
+        let second_album = CollectionSource::from_key("album:abc", second).unwrap();
+
+        assert_eq!(
+            claim(&pool, &first_album, first, Role::Editor)
+                .await
+                .unwrap(),
+            Role::Owner
+        );
+        assert_eq!(
+            claim(&pool, &second_album, second, Role::Editor)
+                .await
+                .unwrap(),
+            Role::Owner
+        );
+    }
+
+    #[sqlx::test]
+    async fn sessions_of_others_need_an_invite(pool: sqlx::PgPool) {
+        let owner = create_user(&pool, "owner").await;
+        let other = create_user(&pool, "other").await;
+        let playlist = CollectionSource::from_key("abc", owner).unwrap();
+
+        // Even before its owner started it
+        assert!(claim(&pool, &playlist, other, Role::Viewer).await.is_err());
+        assert_eq!(role_of(&pool, &playlist.key(), other).await.unwrap(), None);
+
+        claim(&pool, &playlist, owner, Role::Editor).await.unwrap();
+        sqlx::query!(
+            "INSERT INTO ranking_members (playlist_id, user_id, role) VALUES ($1, $2, $3)",
+            playlist.key(),
+            other,
+            Role::Voter.as_str()
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(
+            claim(&pool, &playlist, other, Role::Voter).await.unwrap(),
+            Role::Voter
+        );
+        assert!(claim(&pool, &playlist, other, Role::Editor).await.is_err());
+    }
+}
@@ -0,0 +1,412 @@
+use axum::{Json, Router, extract::State, http::header, response::IntoResponse, routing::get};
+use axum_extra::extract::CookieJar;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    AppState,
+    api_tokens::{ApiUser, Scope},
+    error::ApiError,
+    roles::Role,
+    session::{self, User},
+    spotify::CollectionSource,
+};
+
+#[derive(Debug, Serialize)]
+struct Profile {
+    spotify_id: String,
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+    country: Option<String>,
+    product: Option<String>,
+    profile_updated_at: Option<DateTime<Utc>>,
+}
+
+// Session tokens are left out, only their hashes are stored anyway
+#[derive(Debug, Serialize)]
+struct SessionInfo {
+    id: i32,
+    created_at: Option<DateTime<Utc>>,
+    rotated_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct MatchRecord {
+    playlist_id: String,
+    song_a: String,
+    song_b: String,
+    winner: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct SongRating {
+    playlist_id: String,
+    song_id: String,
+    rating: f64,
+    deviation: f64,
+    volatility: f64,
+    total_matches: i32,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedPlaylist {
+    playlist_id: String,
+    target_playlist_id: String,
+}
+
+// Token hashes are left out as well
+#[derive(Debug, Serialize)]
+struct ApiTokenInfo {
+    name: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Membership {
+    playlist_id: String,
+    role: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct AccountExport {
+    exported_at: DateTime<Utc>,
+    profile: Profile,
+    sessions: Vec<SessionInfo>,
+    api_tokens: Vec<ApiTokenInfo>,
+    rankings: Vec<Membership>,
+    matches: Vec<MatchRecord>,
+    // Ratings are shared by everyone ranking a collection, these are the ones the user voted in
+    ratings: Vec<SongRating>,
+    exported_playlists: Vec<ExportedPlaylist>,
+}
+
+// Everything stored about the user, as a JSON download
+async fn export_account(
+    State(state): State<AppState>,
+    user: ApiUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Scope::Export)?;
+
+    let profile = sqlx::query_as!(
+        Profile,
+        "SELECT spotify_id, display_name, avatar_url, country, product, profile_updated_at FROM users WHERE id = $1",
+        user.id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    let sessions = sqlx::query_as!(
+        SessionInfo,
+        "SELECT id, created_at, rotated_at, expires_at FROM sessions WHERE user_id = $1 ORDER BY created_at",
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let api_tokens = sqlx::query_as!(
+        ApiTokenInfo,
+        "SELECT name, scopes, created_at, last_used_at, expires_at FROM api_tokens WHERE user_id = $1 ORDER BY created_at",
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let rankings = sqlx::query_as!(
+        Membership,
+        "SELECT playlist_id, role, created_at FROM ranking_members WHERE user_id = $1 ORDER BY created_at",
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let matches = sqlx::query_as!(
+        MatchRecord,
+        "SELECT playlist_id, song_a, song_b, winner, created_at FROM matches WHERE user_id = $1 ORDER BY created_at",
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let ratings = sqlx::query_as!(
+        SongRating,
+        "SELECT playlist_id, song_id, rating, deviation, volatility, total_matches FROM songs
+         WHERE playlist_id IN (SELECT playlist_id FROM matches WHERE user_id = $1)
+         ORDER BY playlist_id, rating DESC",
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let exported_playlists = sqlx::query_as!(
+        ExportedPlaylist,
+        "SELECT playlist_id, target_playlist_id FROM exported_playlists WHERE user_id = $1",
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let export = AccountExport {
+        exported_at: Utc::now(),
+        profile,
+        sessions,
+        api_tokens,
+        rankings,
+        matches,
+        ratings,
+        exported_playlists,
+    };
+
+    Ok((
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"spotify-rankings-export.json\"",
+        )],
+        Json(export),
+    ))
+}
+
+// Deletes the user along with their sessions and stored tokens. Votes in rankings others keep stay
+// behind without the user, the ratings there are replayed from them.
+async fn delete_account(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    user: User,
+) -> Result<CookieJar, ApiError> {
+    delete_user(&state.pool, user.id).await?;
+
+    state.spotify.forget_user(&user.spotify_id);
+
+    tracing::info!("Deleted account of {}", user.spotify_id);
+
+    Ok(jar.remove(session::removal_cookie()))
+}
+
+async fn delete_user(pool: &sqlx::PgPool, user_id: i32) -> Result<(), ApiError> {
+    let mut tx = pool.begin().await?;
+
+    // Liked songs and top tracks can't be synced without this account, and rankings nobody else
+    // is a member of would be left without an owner, so both are removed with the account
+    let personal_saved = CollectionSource::SavedTracks(user_id).key();
+    let personal_top = format!("top:%:{}", user_id);
+    let abandoned: Vec<String> = sqlx::query_scalar!(
+        r#"SELECT m.playlist_id AS "playlist_id!" FROM ranking_members m
+           WHERE m.user_id = $1 AND m.role = $2
+             AND NOT EXISTS (SELECT 1 FROM ranking_members o WHERE o.playlist_id = m.playlist_id AND o.user_id <> $1)
+           UNION
+           SELECT playlist_id FROM playlists WHERE playlist_id = $3 OR playlist_id LIKE $4
+           UNION
+           SELECT playlist_id FROM ranking_members WHERE playlist_id = $3 OR playlist_id LIKE $4"#,
+        user_id,
+        Role::Owner.as_str(),
+        personal_saved,
+        personal_top
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM songs WHERE playlist_id = ANY($1)", &abandoned)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "DELETE FROM playlists WHERE playlist_id = ANY($1)",
+        &abandoned
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM matches WHERE playlist_id = ANY($1)",
+        &abandoned
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM exported_playlists WHERE playlist_id = ANY($1)",
+        &abandoned
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM guest_votes WHERE playlist_id = ANY($1)",
+        &abandoned
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM share_links WHERE playlist_id = ANY($1)",
+        &abandoned
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM ranking_members WHERE playlist_id = ANY($1)",
+        &abandoned
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Rankings this user is the only owner of go to the member with the highest role,
+    // the longest standing one on a tie
+    sqlx::query!(
+        "UPDATE ranking_members m SET role = $2
+         FROM (
+             SELECT DISTINCT ON (c.playlist_id) c.playlist_id, c.user_id
+             FROM ranking_members c
+             JOIN ranking_members owned ON owned.playlist_id = c.playlist_id AND owned.user_id = $1 AND owned.role = $2
+             WHERE c.user_id <> $1
+               AND NOT EXISTS (SELECT 1 FROM ranking_members o WHERE o.playlist_id = c.playlist_id AND o.role = $2 AND o.user_id <> $1)
+             ORDER BY c.playlist_id, array_position($3::text[], c.role), c.created_at
+         ) heir
+         WHERE m.playlist_id = heir.playlist_id AND m.user_id = heir.user_id",
+        user_id,
+        Role::Owner.as_str(),
+        &[
+            Role::Editor.as_str().to_string(),
+            Role::Voter.as_str().to_string(),
+            Role::Viewer.as_str().to_string(),
+        ]
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Guests can't keep voting through an account that no longer exists, their votes are kept
+    let revoked = sqlx::query_scalar!(
+        "UPDATE share_links SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL RETURNING id",
+        user_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM guest_sessions WHERE share_link_id = ANY($1)",
+        &revoked
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub fn get_router() -> Router<AppState> {
+    Router::new()
+        .route("/me", axum::routing::delete(delete_account))
+        .route("/me/export", get(export_account))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::matchmaking::replay_ratings;
+
+    const RANKING: &str = "album:shared";
+
+    async fn create_user(pool: &sqlx::PgPool, spotify_id: &str) -> i32 {
+        sqlx::query_scalar!(
+            "INSERT INTO users (spotify_id, access_token, refresh_token, expires_at) VALUES ($1, '', '', NOW()) RETURNING id",
+            spotify_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn ratings(pool: &sqlx::PgPool) -> Vec<(String, f64, i32)> {
+        let mut conn = pool.acquire().await.unwrap();
+        replay_ratings(&mut conn, RANKING).await.unwrap();
+
+        sqlx::query!(
+            "SELECT song_id, rating, total_matches FROM songs WHERE playlist_id = $1 ORDER BY song_id",
+            RANKING
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|song| (song.song_id, song.rating, song.total_matches))
+        .collect()
+    }
+
+    #[sqlx::test]
+    async fn votes_survive_account_deletion(pool: sqlx::PgPool) {
+        let leaving = create_user(&pool, "leaving").await;
+        let staying = create_user(&pool, "staying").await;
+
+        sqlx::query!(
+            "INSERT INTO ranking_members (playlist_id, user_id, role) VALUES ($1, $2, 'owner'), ($1, $3, 'editor')",
+            RANKING,
+            leaving,
+            staying
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "INSERT INTO songs (playlist_id, song_id, rated_since) VALUES ($1, 'a', NOW() - INTERVAL '1 day'), ($1, 'b', NOW() - INTERVAL '1 day'), ($1, 'c', NOW() - INTERVAL '1 day')",
+            RANKING
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        sqlx::query!(
+            "INSERT INTO matches (user_id, playlist_id, song_a, song_b, winner, created_at) VALUES ($1, $3, 'a', 'b', 'a', NOW() - INTERVAL '3 hours'), ($2, $3, 'b', 'c', 'c', NOW() - INTERVAL '2 hours'), ($1, $3, 'a', 'c', 'a', NOW())",
+            leaving,
+            staying,
+            RANKING
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let link = sqlx::query_scalar!(
+            "INSERT INTO share_links (user_id, playlist_id, token_hash, vote_weight) VALUES ($1, $2, 'hash', 0.5) RETURNING id",
+            leaving,
+            RANKING
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "INSERT INTO guest_votes (share_link_id, playlist_id, song_a, song_b, winner, weight, created_at) VALUES ($1, $2, 'b', 'a', 'b', 0.5, NOW() - INTERVAL '1 hour')",
+            link,
+            RANKING
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let before = ratings(&pool).await;
+        delete_user(&pool, leaving).await.unwrap();
+        assert_eq!(ratings(&pool).await, before);
+
+        // The ranking goes to the remaining member, the link stops working
+        let role = sqlx::query_scalar!(
+            "SELECT role FROM ranking_members WHERE playlist_id = $1 AND user_id = $2",
+            RANKING,
+            staying
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(role, Role::Owner.as_str());
+
+        let link = sqlx::query!(
+            "SELECT user_id, revoked_at FROM share_links WHERE id = $1",
+            link
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(link.user_id, None);
+        assert!(link.revoked_at.is_some());
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    routing::get,
+};
+use serde::Serialize;
+
+use crate::{
+    AppState,
+    api_tokens::{ApiUser, Scope},
+    error::ApiError,
+    roles::{self, Role},
+    spotify::{CollectionSource, Spotify},
+};
+
+#[derive(Debug, Serialize)]
+pub struct ArtistRanking {
+    pub artist_id: String,
+    pub name: String,
+    pub image_url: Option<String>,
+    pub genres: Vec<String>,
+    pub songs: i64,
+    pub average_rating: f64,
+    pub top_rating: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GenreRanking {
+    pub genre: String,
+    pub songs: i64,
+    pub average_rating: f64,
+    pub top_rating: f64,
+}
+
+// Store the artists of any songs that haven't been enriched yet
+pub async fn enrich_songs(
+    spotify: &mut Spotify,
+    pool: &sqlx::PgPool,
+    song_ids: &[String],
+) -> Result<(), ApiError> {
+    let enriched: HashSet<String> = sqlx::query_scalar!(
+        "SELECT DISTINCT song_id FROM song_artists WHERE song_id = ANY($1)",
+        song_ids
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+
+    let missing: Vec<String> = song_ids
+        .iter()
+        .filter(|id| !enriched.contains(*id))
+        .cloned()
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let tracks = spotify.get_tracks(&missing).await?;
+
+    let mut pairs: (Vec<String>, Vec<String>) = (Vec::new(), Vec::new());
+    for track in &tracks {
+        for artist_id in track.artists.iter().filter_map(|a| a.id.clone()) {
+            pairs.0.push(track.id.clone());
+            pairs.1.push(artist_id);
+        }
+    }
+
+    // Only fetch artists we haven't seen before
+    let artist_ids: Vec<String> = pairs
+        .1
+        .iter()
+        .cloned()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let known: HashSet<String> = sqlx::query_scalar!(
+        "SELECT artist_id FROM artists WHERE artist_id = ANY($1)",
+        &artist_ids
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+    let unknown: Vec<String> = artist_ids
+        .into_iter()
+        .filter(|id| !known.contains(id))
+        .collect();
+
+    let artists = spotify.get_artists(&unknown).await?;
+
+    // Genre lists differ in length, and Postgres has no ragged arrays, so they are sent as JSON
+    let mut ids = Vec::with_capacity(artists.len());
+    let mut names = Vec::with_capacity(artists.len());
+    let mut genres = Vec::with_capacity(artists.len());
+    let mut image_urls = Vec::with_capacity(artists.len());
+    let mut popularities = Vec::with_capacity(artists.len());
+    for artist in artists {
+        ids.push(artist.id);
+        names.push(artist.name);
+        genres.push(serde_json::to_string(&artist.genres).expect("strings serialize"));
+        image_urls.push(artist.images.into_iter().next().map(|img| img.url));
+        popularities.push(artist.popularity as i32);
+    }
+
+    sqlx::query!(
+        "INSERT INTO artists (artist_id, name, genres, image_url, popularity, updated_at)
+         SELECT a.artist_id, a.name, ARRAY(SELECT jsonb_array_elements_text(a.genres::jsonb)), a.image_url, a.popularity, NOW()
+         FROM UNNEST($1::text[], $2::text[], $3::text[], $4::text[], $5::int4[]) AS a(artist_id, name, genres, image_url, popularity)
+         ON CONFLICT (artist_id)
+         DO UPDATE SET name = EXCLUDED.name, genres = EXCLUDED.genres, image_url = EXCLUDED.image_url, popularity = EXCLUDED.popularity, updated_at = EXCLUDED.updated_at",
+        &ids,
+        &names,
+        &genres,
+        &image_urls as &[Option<String>],
+        &popularities
+    )
+    .execute(pool)
+    .await?;
+
+    // Artists Spotify didn't return are skipped by the join
+    sqlx::query!(
+        "INSERT INTO song_artists (song_id, artist_id)
+         SELECT p.song_id, p.artist_id FROM UNNEST($1::text[], $2::text[]) AS p(song_id, artist_id)
+         JOIN artists a ON a.artist_id = p.artist_id
+         ON CONFLICT DO NOTHING",
+        &pairs.0,
+        &pairs.1
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+async fn get_artist_leaderboard(
+    Path(playlist_id): Path<String>,
+    State(state): State<AppState>,
+    user: ApiUser,
+) -> Result<Json<Vec<ArtistRanking>>, ApiError> {
+    user.require(Scope::Read)?;
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+    roles::require(&state.pool, &playlist_id, user.id, Role::Viewer).await?;
+
+    let artists = sqlx::query_as!(
+        ArtistRanking,
+        r#"SELECT a.artist_id, a.name, a.image_url, a.genres,
+                COUNT(*) AS "songs!", AVG(s.rating) AS "average_rating!", MAX(s.rating) AS "top_rating!"
+         FROM songs s
+         JOIN song_artists sa ON sa.song_id = s.song_id
+         JOIN artists a ON a.artist_id = sa.artist_id
+         WHERE s.playlist_id = $1
+         GROUP BY a.artist_id
+         ORDER BY AVG(s.rating) DESC"#,
+        playlist_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(artists))
+}
+
+async fn get_genre_leaderboard(
+    Path(playlist_id): Path<String>,
+    State(state): State<AppState>,
+    user: ApiUser,
+) -> Result<Json<Vec<GenreRanking>>, ApiError> {
+    user.require(Scope::Read)?;
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+    roles::require(&state.pool, &playlist_id, user.id, Role::Viewer).await?;
+
+    // A song counts once per genre, even if several of its artists share it
+    let genres = sqlx::query_as!(
+        GenreRanking,
+        r#"SELECT genre AS "genre!", COUNT(*) AS "songs!", AVG(rating) AS "average_rating!", MAX(rating) AS "top_rating!"
+         FROM (
+            SELECT DISTINCT s.id, s.rating, genre
+            FROM songs s
+            JOIN song_artists sa ON sa.song_id = s.song_id
+            JOIN artists a ON a.artist_id = sa.artist_id
+            CROSS JOIN UNNEST(a.genres) AS genre
+            WHERE s.playlist_id = $1
+         ) song_genres
+         GROUP BY genre
+         ORDER BY AVG(rating) DESC"#,
+        playlist_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(genres))
+}
+
+pub fn get_router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/playlists/{playlist_id}/leaderboard/artists",
+            get(get_artist_leaderboard),
+        )
+        .route(
+            "/playlists/{playlist_id}/leaderboard/genres",
+            get(get_genre_leaderboard),
+        )
+}
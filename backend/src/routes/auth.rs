@@ -1,74 +1,188 @@
 use axum::{
-    Router,
+    Json, Router,
     extract::{Query, State},
     response::{IntoResponse, Redirect},
-    routing::get,
+    routing::{get, post},
 };
-use axum_extra::extract::{CookieJar, cookie::Cookie};
-use reqwest::StatusCode;
-use serde::Deserialize;
+use axum_extra::extract::{
+    CookieJar,
+    cookie::{Cookie, SameSite},
+};
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     AppState,
-    spotify::{Spotify, SpotifyResponse},
+    error::ApiError,
+    session::{self, User},
 };
 
+const SCOPES: &[&str] = &[
+    "playlist-read-private",
+    "playlist-read-collaborative",
+    "playlist-modify-private",
+    "playlist-modify-public",
+    "user-library-read",
+    "user-top-read",
+    "user-read-private",
+    "streaming",
+];
+
+const OAUTH_STATE_COOKIE: &str = "oauth_state";
+
+// How long a login can take before the state expires
+const OAUTH_STATE_MINUTES: i64 = 10;
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    // Where to send the user after logging in, must be on the frontend
+    return_to: Option<String>,
+}
+
+// Spotify sends either a code or an error back, plus the state from the login
 #[derive(Debug, Deserialize)]
 struct Callback {
-    code: String,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
 }
 
-async fn login(State(state): State<AppState>) -> impl IntoResponse {
+// Only allow redirects back to the frontend, anything else would make login an open redirect
+fn validate_return_to(frontend_url: &Url, return_to: &str) -> Option<Url> {
+    let url = frontend_url.join(return_to).ok()?;
+    (url.origin() == frontend_url.origin()).then_some(url)
+}
+
+// Lax so the cookie is still sent on the redirect back from Spotify
+fn state_cookie(csrf_state: String) -> Cookie<'static> {
+    Cookie::build((OAUTH_STATE_COOKIE, csrf_state))
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::minutes(OAUTH_STATE_MINUTES))
+        .build()
+}
+
+// The state has to come from this browser, otherwise someone else's code could be injected
+fn check_state(jar: &CookieJar, state: &str) -> Result<(), ApiError> {
+    match jar.get(OAUTH_STATE_COOKIE) {
+        Some(cookie) if cookie.value() == state => Ok(()),
+        _ => Err(ApiError::BadRequest("Invalid OAuth state")),
+    }
+}
+
+async fn login(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    Query(query): Query<LoginQuery>,
+) -> Result<impl IntoResponse, ApiError> {
     tracing::info!("Received login request");
-    Redirect::to(&format!(
-        "https://accounts.spotify.com/authorize?response_type=code&client_id={}&redirect_uri={}&scope=playlist-read-private,playlist-read-collaborative,streaming",
-        state.client_id, state.redirect_uri
-    ))
+
+    let return_to = match &query.return_to {
+        Some(return_to) => validate_return_to(&state.frontend_url, return_to)
+            .ok_or(ApiError::BadRequest("return_to must be on the frontend"))?,
+        None => state.frontend_url.clone(),
+    };
+
+    let (url, csrf_state, pkce_verifier) = state.spotify.authorize_url(SCOPES);
+
+    // Clean up logins that were never finished
+    sqlx::query!(
+        "DELETE FROM oauth_states WHERE created_at < NOW() - make_interval(mins => $1)",
+        OAUTH_STATE_MINUTES as i32
+    )
+    .execute(&state.pool)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO oauth_states (state, pkce_verifier, return_to) VALUES ($1, $2, $3)",
+        csrf_state.secret(),
+        pkce_verifier.secret(),
+        return_to.as_str()
+    )
+    .execute(&state.pool)
+    .await?;
+
+    let jar = jar.add(state_cookie(csrf_state.secret().clone()));
+
+    Ok((jar, Redirect::to(url.as_str())))
 }
 
+// The callback is a browser navigation, so errors are sent back to the frontend as `login_error`
 async fn callback(
     State(state): State<AppState>,
+    jar: CookieJar,
     Query(params): Query<Callback>,
-) -> Result<CookieJar, (StatusCode, String)> {
-    tracing::info!("Received callback with code: {}", params.code);
+) -> (CookieJar, Redirect) {
+    let result = match params.error {
+        // The user declined access on Spotify's consent screen
+        Some(error) => Err(error),
+        None => complete_login(&state, &jar, params).await.map_err(|e| {
+            tracing::warn!("Login failed: {}", e);
+            e.code().to_string()
+        }),
+    };
+
+    let jar = jar.remove(Cookie::build(OAUTH_STATE_COOKIE).path("/"));
+
+    match result {
+        Ok((token, return_to)) => (
+            jar.add(session::cookie(token)),
+            Redirect::to(return_to.as_str()),
+        ),
+        Err(error) => {
+            let mut url = state.frontend_url.clone();
+            url.query_pairs_mut().append_pair("login_error", &error);
+            (jar, Redirect::to(url.as_str()))
+        }
+    }
+}
+
+// Exchanges the code for tokens and starts a session, returning its token and where to go next
+async fn complete_login(
+    state: &AppState,
+    jar: &CookieJar,
+    params: Callback,
+) -> Result<(String, Url), ApiError> {
+    tracing::info!("Received login callback");
+
+    let (Some(code), Some(params_state)) = (params.code, params.state) else {
+        return Err(ApiError::BadRequest("Missing code or state"));
+    };
+
+    check_state(jar, &params_state)?;
+
+    // Each state can only be used once
+    let pending = sqlx::query!(
+        "DELETE FROM oauth_states WHERE state = $1 AND created_at > NOW() - make_interval(mins => $2) RETURNING pkce_verifier, return_to",
+        params_state,
+        OAUTH_STATE_MINUTES as i32
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or(ApiError::BadRequest("Invalid OAuth state"))?;
+
+    // Validated again in case the frontend URL changed since the login started
+    let return_to = pending
+        .return_to
+        .and_then(|return_to| validate_return_to(&state.frontend_url, &return_to))
+        .unwrap_or_else(|| state.frontend_url.clone());
 
     let response = state
-        .client
-        .post("https://accounts.spotify.com/api/token")
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .basic_auth(&state.client_id, Some(&state.client_secret))
-        .form(&[
+        .spotify
+        .request_token(&[
             ("grant_type", "authorization_code"),
-            ("code", &params.code),
-            ("redirect_uri", &state.redirect_uri),
+            ("code", &code),
+            ("redirect_uri", &state.spotify.redirect_uri),
+            ("code_verifier", &pending.pkce_verifier),
         ])
-        .send()
-        .await
-        .map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to send request".into(),
-            )
-        })?
-        .json::<SpotifyResponse>()
-        .await
-        .map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to parse response".into(),
-            )
-        })?;
-
-    let spotify = Spotify::from_response(response, &state)
-        .await
-        .map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to create Spotify client".into(),
-            )
-        })?;
-
-    let token = rand::random::<u64>().to_string();
+        .await?;
+
+    let spotify = state.spotify.login(response).await?;
+
+    let token = session::generate_token();
 
     // Get the id from the database
     let user_id = sqlx::query_scalar!(
@@ -76,46 +190,221 @@ async fn callback(
         spotify.spotify_id
     )
     .fetch_one(&state.pool)
-    .await
-    .map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to fetch user id".into(),
-        )
-    })?;
+    .await?;
 
     // Create a new session in the database session table
     sqlx::query!(
-        "INSERT INTO sessions (user_id, token, created_at) VALUES ($1, $2, NOW())",
+        "INSERT INTO sessions (user_id, token_hash, created_at, expires_at) VALUES ($1, $2, NOW(), NOW() + make_interval(days => $3))",
         user_id,
-        token
+        session::hash_token(&token),
+        session::SESSION_DAYS
     )
     .execute(&state.pool)
-    .await
-    .map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to create session".into(),
-        )
-    })?;
-
-    let jar = CookieJar::new();
-    let jar = jar.add(
-        Cookie::build(("session_token", token))
-            .secure(true)
-            .http_only(true)
-            .max_age(time::Duration::days(30)),
+    .await?;
+
+    Ok((token, return_to))
+}
+
+// Ends the current session
+async fn logout(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    user: User,
+) -> Result<CookieJar, ApiError> {
+    sqlx::query!("DELETE FROM sessions WHERE id = $1", user.session_id)
+        .execute(&state.pool)
+        .await?;
+
+    Ok(jar.remove(session::removal_cookie()))
+}
+
+// Ends every session of the user, on all devices
+async fn logout_everywhere(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    user: User,
+) -> Result<CookieJar, ApiError> {
+    let result = sqlx::query!("DELETE FROM sessions WHERE user_id = $1", user.id)
+        .execute(&state.pool)
+        .await?;
+
+    tracing::info!(
+        "Logged out {} sessions of {}",
+        result.rows_affected(),
+        user.spotify_id
     );
 
-    Ok(jar)
+    Ok(jar.remove(session::removal_cookie()))
 }
 
-// Returns OK if they are logged in
-async fn me(_: Spotify) {}
+// How long the cached Spotify profile is used before fetching it again
+const PROFILE_TTL_HOURS: i32 = 24;
+
+#[derive(Debug, Serialize)]
+struct Me {
+    spotify_id: String,
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+    country: Option<String>,
+    product: Option<String>,
+    playlists_ranked: i64,
+    total_votes: i64,
+    last_active: Option<DateTime<Utc>>,
+}
+
+// The logged in user's profile and stats, also used by the frontend to check the session
+async fn me(State(state): State<AppState>, user: User) -> Result<Json<Me>, ApiError> {
+    let stale = sqlx::query_scalar!(
+        r#"SELECT profile_updated_at IS NULL OR profile_updated_at < NOW() - make_interval(hours => $2) AS "stale!"
+         FROM users WHERE id = $1"#,
+        user.id,
+        PROFILE_TTL_HOURS
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    // A stale profile is better than none, unless the user has to log in again anyway
+    if stale {
+        let result = match state.spotify.for_user(user.id).await {
+            Ok(mut spotify) => spotify.update_profile().await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Err(ApiError::ReauthRequired) => return Err(ApiError::ReauthRequired),
+            Err(e) => tracing::warn!("Failed to update profile of {}: {}", user.spotify_id, e),
+            Ok(()) => {}
+        }
+    }
+
+    let me = sqlx::query_as!(
+        Me,
+        r#"SELECT u.spotify_id, u.display_name, u.avatar_url, u.country, u.product,
+                (SELECT COUNT(DISTINCT playlist_id) FROM matches WHERE user_id = u.id) AS "playlists_ranked!",
+                (SELECT COUNT(*) FROM matches WHERE user_id = u.id) AS "total_votes!",
+                GREATEST(
+                    (SELECT MAX(created_at) FROM matches WHERE user_id = u.id),
+                    (SELECT MAX(GREATEST(created_at, rotated_at)) FROM sessions WHERE user_id = u.id)
+                ) AS last_active
+         FROM users u
+         WHERE u.id = $1"#,
+        user.id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    Ok(Json(me))
+}
+
+#[derive(Debug, Serialize)]
+struct PlaybackToken {
+    access_token: String,
+    expires_at: DateTime<Utc>,
+}
+
+// Hands the frontend an access token for the Web Playback SDK, which needs the `streaming` scope
+async fn playback_token(
+    State(state): State<AppState>,
+    user: User,
+) -> Result<Json<PlaybackToken>, ApiError> {
+    let mut spotify = state.spotify.for_user(user.id).await?;
+    spotify.refresh().await?;
+
+    Ok(Json(PlaybackToken {
+        access_token: spotify.access_token,
+        expires_at: spotify.expires_at,
+    }))
+}
 
 pub fn get_router() -> Router<AppState> {
     Router::new()
         .route("/login", get(login))
         .route("/callback", get(callback))
+        .route("/logout", post(logout))
+        .route("/logout/all", post(logout_everywhere))
         .route("/me", get(me))
+        .route("/me/playback-token", get(playback_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Round trips the cookie through headers, the way the browser sends it back on the callback
+    fn returned_jar(cookie: &Cookie) -> CookieJar {
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(
+            axum::http::header::COOKIE,
+            cookie.stripped().encoded().to_string().parse().unwrap(),
+        );
+        CookieJar::from_headers(&headers)
+    }
+
+    #[test]
+    fn state_cookie_is_sent_back_on_callback() {
+        let cookie = state_cookie("abc123".to_string());
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+
+        let jar = returned_jar(&cookie);
+        assert!(check_state(&jar, "abc123").is_ok());
+    }
+
+    #[test]
+    fn state_must_match_cookie() {
+        let jar = returned_jar(&state_cookie("abc123".to_string()));
+        assert!(check_state(&jar, "other").is_err());
+        assert!(check_state(&jar, "").is_err());
+    }
+
+    #[test]
+    fn state_requires_cookie() {
+        assert!(check_state(&CookieJar::new(), "abc123").is_err());
+    }
+
+    fn frontend() -> Url {
+        Url::parse("https://rankings.example").unwrap()
+    }
+
+    #[test]
+    fn return_to_rejects_other_origins() {
+        for return_to in [
+            "//evil.com",
+            "/\\evil.com",
+            "\\\\evil.com",
+            "\t//evil.com",
+            "https://evil.com/playlists",
+            "http://rankings.example/",
+            "https://rankings.example.evil.com/",
+            "https://rankings.example@evil.com/",
+            "javascript:alert(1)",
+        ] {
+            assert_eq!(
+                validate_return_to(&frontend(), return_to),
+                None,
+                "{return_to}"
+            );
+        }
+    }
+
+    #[test]
+    fn return_to_keeps_encoded_paths_on_the_frontend() {
+        for return_to in ["%2F%2Fevil.com", "/%2F%2Fevil.com", "/%5Cevil.com"] {
+            let url = validate_return_to(&frontend(), return_to).unwrap();
+            assert_eq!(url.host_str(), Some("rankings.example"), "{return_to}");
+        }
+    }
+
+    #[test]
+    fn return_to_accepts_relative_paths() {
+        assert_eq!(
+            validate_return_to(&frontend(), "/playlists/abc?x=1").map(String::from),
+            Some("https://rankings.example/playlists/abc?x=1".to_string())
+        );
+        assert_eq!(
+            validate_return_to(&frontend(), "https://rankings.example/playlists").map(String::from),
+            Some("https://rankings.example/playlists".to_string())
+        );
+    }
 }
@@ -1,11 +1,13 @@
+use std::collections::{HashMap, HashSet};
+
 use axum::{
     Json, Router,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     routing::get,
 };
+use chrono::{DateTime, Utc};
 use rand::prelude::*;
 use rand_distr::weighted::WeightedIndex;
-use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use skillratings::{
     Outcomes,
@@ -14,43 +16,106 @@ use skillratings::{
 
 use crate::{
     AppState,
+    api_tokens::{ApiUser, Scope},
+    error::ApiError,
+    roles::{self, Role},
     routes::playlists::{RatedTrack, Song},
-    spotify::Spotify,
+    spotify::CollectionSource,
 };
 
 #[derive(Debug, Serialize)]
-struct Match {
+pub struct Match {
     song_a: RatedTrack,
     song_b: RatedTrack,
+    // Suggested positions to start auditioning each song from
+    song_a_start_ms: u32,
+    song_b_start_ms: u32,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GenreMode {
+    // Only pair songs that share a genre
+    Same,
+    // Only pair songs that have no genre in common
+    Different,
 }
 
 #[derive(Debug, Deserialize)]
-struct MatchResult {
-    song_a: String,
-    song_b: String,
-    winner: String,
+pub struct MatchmakingQuery {
+    genres: Option<GenreMode>,
+    // Leave out songs that can't be played in the user's market, otherwise they are only flagged
+    #[serde(default)]
+    exclude_unplayable: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MatchResult {
+    pub song_a: String,
+    pub song_b: String,
+    pub winner: String,
 }
 
 const EPSILON: f64 = 0.0001;
 
-async fn matchmaking(
-    State(state): State<AppState>,
-    Path(playlist_id): Path<String>,
-    mut spotify: Spotify,
-) -> Result<Json<Match>, StatusCode> {
-    let songs = sqlx::query_as!(
+// How long each song is expected to be auditioned for before voting
+const AUDITION_MS: u32 = 30_000;
+
+// Skip the intro, which is rarely what makes a song, but leave enough time to audition
+fn suggested_start_ms(duration_ms: u32) -> u32 {
+    let start = duration_ms / 3;
+    start.min(duration_ms.saturating_sub(AUDITION_MS))
+}
+
+// Pick two songs of a collection to compare, the tracks are loaded with the given user's Spotify
+pub async fn find_match(
+    state: &AppState,
+    user_id: i32,
+    playlist_id: &str,
+    query: &MatchmakingQuery,
+) -> Result<Match, ApiError> {
+    let mut spotify = state.spotify.for_user(user_id).await?;
+
+    let mut songs = sqlx::query_as!(
         Song,
-        "SELECT id, song_id, playlist_id, rating, deviation, volatility, total_matches FROM songs WHERE playlist_id = $1 ORDER BY rating DESC",
+        "SELECT id, song_id, playlist_id, rating, deviation, volatility, total_matches, is_playable FROM songs WHERE playlist_id = $1 ORDER BY rating DESC",
         playlist_id
     )
     .fetch_all(&state.pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .await?;
+
+    // Songs with unknown availability are kept
+    if query.exclude_unplayable {
+        songs.retain(|song| song.is_playable != Some(false));
+    }
 
     if songs.len() < 2 {
-        return Err(StatusCode::BAD_REQUEST); // Not enough songs to make a match
+        return Err(ApiError::BadRequest("Not enough songs to make a match"));
     }
 
+    // Genres of every song, only needed when pairing by genre
+    let genres: HashMap<String, HashSet<String>> = match query.genres {
+        Some(_) => sqlx::query!(
+            r#"SELECT sa.song_id, ARRAY_AGG(DISTINCT genre) AS "genres!"
+             FROM song_artists sa
+             JOIN artists a ON a.artist_id = sa.artist_id
+             CROSS JOIN UNNEST(a.genres) AS genre
+             WHERE sa.song_id = ANY($1)
+             GROUP BY sa.song_id"#,
+            &songs.iter().map(|s| s.song_id.clone()).collect::<Vec<_>>()
+        )
+        .fetch_all(&state.pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.song_id, row.genres.into_iter().collect()))
+        .collect(),
+        None => HashMap::new(),
+    };
+    let shares_genre = |a: &Song, b: &Song| match (genres.get(&a.song_id), genres.get(&b.song_id)) {
+        (Some(a), Some(b)) => !a.is_disjoint(b),
+        _ => false,
+    };
+
     // Do all random operations first to avoid Send issues
     let (song_a_idx, song_b_idx) = {
         let mut rng = rand::rng();
@@ -65,23 +130,34 @@ async fn matchmaking(
         let mut weights = Vec::new();
         let mut candidates = Vec::new();
 
+        let allowed = |song: &Song| match query.genres {
+            Some(GenreMode::Same) => shares_genre(song_a, song),
+            Some(GenreMode::Different) => !shares_genre(song_a, song),
+            None => true,
+        };
+        // Fall back to any song if none match the genre mode
+        let any_allowed = songs
+            .iter()
+            .enumerate()
+            .any(|(idx, song)| idx != song_a_idx && allowed(song));
+
         for (idx, song) in songs.iter().enumerate() {
             if idx == song_a_idx {
                 continue; // Skip the same song
             }
 
+            if any_allowed && !allowed(song) {
+                continue;
+            }
+
             let dist = (song.rating - song_a.rating).powi(2);
             let weight = EPSILON + (-dist / (2.0 * song_a.deviation.powi(2))).exp();
             weights.push(weight);
             candidates.push(idx);
         }
 
-        // Print all songs and weights for debugging
-        for (weight, candidate) in weights.iter().zip(&candidates) {
-            tracing::info!("Weight for song {}: {}", songs[*candidate].song_id, weight);
-        }
-
-        let dist = WeightedIndex::new(&weights).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let dist = WeightedIndex::new(&weights)
+            .map_err(|_| ApiError::BadRequest("Unable to weigh match candidates"))?;
         let candidate_idx = dist.sample(&mut rng);
         let song_b_idx = candidates[candidate_idx];
 
@@ -98,25 +174,51 @@ async fn matchmaking(
     );
 
     let songs = spotify
-        .get_tracks(&state, &[song_a.song_id.clone(), song_b.song_id.clone()])
-        .await
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        .get_tracks(&[song_a.song_id.clone(), song_b.song_id.clone()])
+        .await?;
 
-    let rated_song_a = RatedTrack::from_track(&songs[0], song_a);
-    let rated_song_b = RatedTrack::from_track(&songs[1], song_b);
+    // Tracks that are no longer on Spotify are left out of the response
+    let rated = |song: &Song| {
+        songs
+            .iter()
+            .find(|track| track.id == song.song_id)
+            .map(|track| RatedTrack::from_track(track, song))
+            .ok_or(ApiError::NotFound("Song is no longer available on Spotify"))
+    };
+    let rated_song_a = rated(song_a)?;
+    let rated_song_b = rated(song_b)?;
 
-    Ok(Json(Match {
+    Ok(Match {
+        song_a_start_ms: suggested_start_ms(rated_song_a.duration_ms),
+        song_b_start_ms: suggested_start_ms(rated_song_b.duration_ms),
         song_a: rated_song_a,
         song_b: rated_song_b,
-    }))
+    })
 }
 
-async fn matchmaking_result(
+async fn matchmaking(
     State(state): State<AppState>,
-    _: Spotify,
-    Json(result): Json<MatchResult>,
-) -> Result<(), StatusCode> {
-    // Update the ratings based on the result
+    Path(playlist_id): Path<String>,
+    Query(query): Query<MatchmakingQuery>,
+    user: ApiUser,
+) -> Result<Json<Match>, ApiError> {
+    user.require(Scope::Vote)?;
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+    roles::require(&state.pool, &playlist_id, user.id, Role::Voter).await?;
+
+    Ok(Json(
+        find_match(&state, user.id, &playlist_id, &query).await?,
+    ))
+}
+
+// Update the ratings of both songs, `weight` scales how far they move so 0 leaves them as is
+// Must run in the transaction that records the vote, the songs stay locked until it ends
+pub async fn rate_match(
+    conn: &mut sqlx::PgConnection,
+    playlist_id: &str,
+    result: &MatchResult,
+    weight: f64,
+) -> Result<(), ApiError> {
     tracing::info!(
         "Match result: A({}) vs B({}), winner: {}",
         result.song_a,
@@ -124,58 +226,229 @@ async fn matchmaking_result(
         result.winner
     );
 
-    // Create glicko2 players for both songs
-    let player_a = sqlx::query_as!(
-        Glicko2Rating,
-        "SELECT rating, deviation, volatility FROM songs WHERE song_id = $1",
-        result.song_a
-    )
-    .fetch_one(&state.pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let player_b = sqlx::query_as!(
-        Glicko2Rating,
-        "SELECT rating, deviation, volatility FROM songs WHERE song_id = $1",
+    // Lock both songs so concurrent votes on them are applied one after the other, in id order
+    // so two votes on the same pair can't deadlock
+    let songs = sqlx::query!(
+        "SELECT song_id, rating, deviation, volatility FROM songs WHERE playlist_id = $1 AND song_id IN ($2, $3) ORDER BY id FOR UPDATE",
+        playlist_id,
+        result.song_a,
         result.song_b
     )
-    .fetch_one(&state.pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let outcome = if result.winner == result.song_a {
-        Outcomes::WIN
-    } else if result.winner == result.song_b {
-        Outcomes::LOSS
-    } else {
-        return Err(StatusCode::BAD_REQUEST); // Invalid winner
+    .fetch_all(&mut *conn)
+    .await?;
+
+    // Create glicko2 players for both songs
+    let player = |song_id: &str| {
+        songs
+            .iter()
+            .find(|song| song.song_id == song_id)
+            .map(|song| Glicko2Rating {
+                rating: song.rating,
+                deviation: song.deviation,
+                volatility: song.volatility,
+            })
+            .ok_or(ApiError::BadRequest("Song is not in this playlist"))
     };
+    let player_a = player(&result.song_a)?;
+    let player_b = player(&result.song_b)?;
 
-    let config = skillratings::glicko2::Glicko2Config::default();
-    let (new_player_a, new_player_b) = glicko2(&player_a, &player_b, &outcome, &config);
+    let outcome = outcome(&result.song_a, &result.song_b, &result.winner)
+        .ok_or(ApiError::BadRequest("Winner must be one of the two songs"))?;
+
+    if weight <= 0.0 {
+        return Ok(());
+    }
+
+    let (new_player_a, new_player_b) = play(&player_a, &player_b, &outcome, weight);
 
     // Update the database with the new ratings
     sqlx::query!(
-        "UPDATE songs SET rating = $1, deviation = $2, volatility = $3, total_matches = total_matches + 1 WHERE song_id = $4",
+        "UPDATE songs SET rating = $1, deviation = $2, volatility = $3, total_matches = total_matches + 1 WHERE song_id = $4 AND playlist_id = $5",
         new_player_a.rating,
         new_player_a.deviation,
         new_player_a.volatility,
-        result.song_a
+        result.song_a,
+        playlist_id
     )
-    .execute(&state.pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .execute(&mut *conn)
+    .await?;
 
     sqlx::query!(
-        "UPDATE songs SET rating = $1, deviation = $2, volatility = $3, total_matches = total_matches + 1 WHERE song_id = $4",
+        "UPDATE songs SET rating = $1, deviation = $2, volatility = $3, total_matches = total_matches + 1 WHERE song_id = $4 AND playlist_id = $5",
         new_player_b.rating,
         new_player_b.deviation,
         new_player_b.volatility,
-        result.song_b
+        result.song_b,
+        playlist_id
     )
-    .execute(&state.pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Rebuilds the ratings of a collection from the base ratings and the votes recorded since,
+/// counting guest votes with the weight their link has now
+pub async fn replay_ratings(
+    conn: &mut sqlx::PgConnection,
+    playlist_id: &str,
+) -> Result<(), ApiError> {
+    // Locked like in `rate_match`, so no vote is lost while the ratings are rebuilt
+    let mut songs: HashMap<String, ReplayedSong> = sqlx::query!(
+        "SELECT song_id, base_rating, base_deviation, base_volatility, base_matches, rated_since
+         FROM songs WHERE playlist_id = $1 ORDER BY id FOR UPDATE",
+        playlist_id
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|song| {
+        let replayed = ReplayedSong {
+            rating: Glicko2Rating {
+                rating: song.base_rating,
+                deviation: song.base_deviation,
+                volatility: song.base_volatility,
+            },
+            total_matches: song.base_matches,
+            rated_since: song.rated_since,
+        };
+        (song.song_id, replayed)
+    })
+    .collect();
+
+    let votes = sqlx::query!(
+        r#"SELECT song_a AS "song_a!", song_b AS "song_b!", winner AS "winner!", weight AS "weight!", created_at AS "created_at!"
+         FROM (
+             SELECT song_a, song_b, winner, 1::float8 AS weight, created_at, 0 AS source, id FROM matches WHERE playlist_id = $1
+             UNION ALL
+             SELECT song_a, song_b, winner, weight, created_at, 1 AS source, id FROM guest_votes WHERE playlist_id = $1
+         ) votes
+         ORDER BY created_at, source, id"#,
+        playlist_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for vote in votes {
+        let Some(outcome) = outcome(&vote.song_a, &vote.song_b, &vote.winner) else {
+            continue;
+        };
+        // Songs that have been removed from the collection since are skipped
+        let (Some(song_a), Some(song_b)) = (songs.get(&vote.song_a), songs.get(&vote.song_b))
+        else {
+            continue;
+        };
+        if vote.weight <= 0.0 {
+            continue;
+        }
+
+        let (new_a, new_b) = play(&song_a.rating, &song_b.rating, &outcome, vote.weight);
+
+        for (song_id, rating) in [(&vote.song_a, new_a), (&vote.song_b, new_b)] {
+            let song = songs.get_mut(song_id).expect("both songs were found above");
+            if vote.created_at > song.rated_since {
+                song.rating = rating;
+                song.total_matches += 1;
+            }
+        }
+    }
+
+    let mut song_ids = Vec::with_capacity(songs.len());
+    let mut ratings = Vec::with_capacity(songs.len());
+    let mut deviations = Vec::with_capacity(songs.len());
+    let mut volatilities = Vec::with_capacity(songs.len());
+    let mut total_matches = Vec::with_capacity(songs.len());
+    for (song_id, song) in songs {
+        song_ids.push(song_id);
+        ratings.push(song.rating.rating);
+        deviations.push(song.rating.deviation);
+        volatilities.push(song.rating.volatility);
+        total_matches.push(song.total_matches);
+    }
+
+    sqlx::query!(
+        "UPDATE songs s SET rating = r.rating, deviation = r.deviation, volatility = r.volatility, total_matches = r.total_matches
+         FROM UNNEST($2::text[], $3::float8[], $4::float8[], $5::float8[], $6::int4[]) AS r(song_id, rating, deviation, volatility, total_matches)
+         WHERE s.playlist_id = $1 AND s.song_id = r.song_id",
+        playlist_id,
+        &song_ids,
+        &ratings,
+        &deviations,
+        &volatilities,
+        &total_matches
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+struct ReplayedSong {
+    rating: Glicko2Rating,
+    total_matches: i32,
+    rated_since: DateTime<Utc>,
+}
+
+// The outcome for song A, or None if the winner isn't one of the two songs
+fn outcome(song_a: &str, song_b: &str, winner: &str) -> Option<Outcomes> {
+    if winner == song_a {
+        Some(Outcomes::WIN)
+    } else if winner == song_b {
+        Some(Outcomes::LOSS)
+    } else {
+        None
+    }
+}
+
+// The new ratings of both songs after a vote that counts for `weight`
+fn play(
+    song_a: &Glicko2Rating,
+    song_b: &Glicko2Rating,
+    outcome: &Outcomes,
+    weight: f64,
+) -> (Glicko2Rating, Glicko2Rating) {
+    let config = skillratings::glicko2::Glicko2Config::default();
+    let (new_a, new_b) = glicko2(song_a, song_b, outcome, &config);
+
+    (weigh(song_a, &new_a, weight), weigh(song_b, &new_b, weight))
+}
+
+// Move a rating only part of the way towards its updated value
+fn weigh(old: &Glicko2Rating, new: &Glicko2Rating, weight: f64) -> Glicko2Rating {
+    let weight = weight.min(1.0);
+
+    Glicko2Rating {
+        rating: old.rating + (new.rating - old.rating) * weight,
+        deviation: old.deviation + (new.deviation - old.deviation) * weight,
+        volatility: old.volatility + (new.volatility - old.volatility) * weight,
+    }
+}
+
+async fn matchmaking_result(
+    State(state): State<AppState>,
+    Path(playlist_id): Path<String>,
+    user: ApiUser,
+    Json(result): Json<MatchResult>,
+) -> Result<(), ApiError> {
+    user.require(Scope::Vote)?;
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+    roles::require(&state.pool, &playlist_id, user.id, Role::Voter).await?;
+
+    let mut tx = state.pool.begin().await?;
+    rate_match(&mut tx, &playlist_id, &result, 1.0).await?;
+
+    sqlx::query!(
+        "INSERT INTO matches (user_id, playlist_id, song_a, song_b, winner) VALUES ($1, $2, $3, $4, $5)",
+        user.id,
+        playlist_id,
+        result.song_a,
+        result.song_b,
+        result.winner
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
 
     Ok(())
 }
@@ -0,0 +1,162 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    routing::{delete, get},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    error::ApiError,
+    roles::{self, Role},
+    routes::shares,
+    session::User,
+    spotify::CollectionSource,
+};
+
+#[derive(Debug, Serialize)]
+struct Member {
+    spotify_id: String,
+    display_name: Option<String>,
+    role: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Invite {
+    spotify_id: String,
+    role: Role,
+}
+
+async fn list_members(
+    State(state): State<AppState>,
+    Path(playlist_id): Path<String>,
+    user: User,
+) -> Result<Json<Vec<Member>>, ApiError> {
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+    roles::require(&state.pool, &playlist_id, user.id, Role::Viewer).await?;
+
+    let members = sqlx::query_as!(
+        Member,
+        "SELECT u.spotify_id, u.display_name, m.role, m.created_at
+         FROM ranking_members m
+         JOIN users u ON u.id = m.user_id
+         WHERE m.playlist_id = $1
+         ORDER BY m.created_at",
+        playlist_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(members))
+}
+
+// Add a member or change their role, they need to have logged in once so they have an account
+// Members who can no longer share the ranking lose the links they created
+async fn invite_member(
+    State(state): State<AppState>,
+    Path(playlist_id): Path<String>,
+    user: User,
+    Json(invite): Json<Invite>,
+) -> Result<(), ApiError> {
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+    roles::require(&state.pool, &playlist_id, user.id, Role::Owner).await?;
+
+    if invite.spotify_id == user.spotify_id {
+        return Err(ApiError::BadRequest("You can't change your own role"));
+    }
+
+    let invitee = sqlx::query_scalar!(
+        "SELECT id FROM users WHERE spotify_id = $1",
+        invite.spotify_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or(ApiError::NotFound(
+        "User not found, they have to log in once first",
+    ))?;
+
+    sqlx::query!(
+        "INSERT INTO ranking_members (playlist_id, user_id, role, invited_by) VALUES ($1, $2, $3, $4)
+         ON CONFLICT (playlist_id, user_id) DO UPDATE SET role = EXCLUDED.role, invited_by = EXCLUDED.invited_by",
+        playlist_id,
+        invitee,
+        invite.role.as_str(),
+        user.id
+    )
+    .execute(&state.pool)
+    .await?;
+
+    if invite.role < Role::Editor {
+        shares::revoke_links_of(&state.pool, &playlist_id, invitee).await?;
+    }
+
+    Ok(())
+}
+
+// Owners can remove anyone and members can remove themselves, as long as an owner is left
+async fn revoke_member(
+    State(state): State<AppState>,
+    Path((playlist_id, spotify_id)): Path<(String, String)>,
+    user: User,
+) -> Result<(), ApiError> {
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+
+    let required = if spotify_id == user.spotify_id {
+        Role::Viewer
+    } else {
+        Role::Owner
+    };
+    roles::require(&state.pool, &playlist_id, user.id, required).await?;
+
+    let member = sqlx::query!(
+        "SELECT m.user_id, m.role FROM ranking_members m
+         JOIN users u ON u.id = m.user_id
+         WHERE m.playlist_id = $1 AND u.spotify_id = $2",
+        playlist_id,
+        spotify_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or(ApiError::NotFound("Member not found"))?;
+
+    if Role::parse(&member.role) == Some(Role::Owner) {
+        let other_owners = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM ranking_members WHERE playlist_id = $1 AND role = $2 AND user_id <> $3"#,
+            playlist_id,
+            Role::Owner.as_str(),
+            member.user_id
+        )
+        .fetch_one(&state.pool)
+        .await?;
+
+        if other_owners == 0 {
+            return Err(ApiError::Conflict("A ranking needs at least one owner"));
+        }
+    }
+
+    sqlx::query!(
+        "DELETE FROM ranking_members WHERE playlist_id = $1 AND user_id = $2",
+        playlist_id,
+        member.user_id
+    )
+    .execute(&state.pool)
+    .await?;
+
+    shares::revoke_links_of(&state.pool, &playlist_id, member.user_id).await?;
+
+    Ok(())
+}
+
+pub fn get_router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/playlists/{playlist_id}/members",
+            get(list_members).post(invite_member),
+        )
+        .route(
+            "/playlists/{playlist_id}/members/{spotify_id}",
+            delete(revoke_member),
+        )
+}
@@ -2,13 +2,23 @@ use axum::Router;
 
 use crate::AppState;
 
+pub mod account;
+pub mod artists;
 pub mod auth;
 pub mod matchmaking;
+pub mod members;
 pub mod playlists;
+pub mod shares;
+pub mod tokens;
 
 pub fn get_router() -> Router<AppState> {
     Router::new()
+        .merge(account::get_router())
+        .merge(artists::get_router())
         .merge(auth::get_router())
         .merge(playlists::get_router())
         .merge(matchmaking::get_router())
+        .merge(members::get_router())
+        .merge(shares::get_router())
+        .merge(tokens::get_router())
 }
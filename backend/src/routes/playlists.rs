@@ -1,16 +1,26 @@
-use std::collections::HashSet;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{HashMap, HashSet},
+};
 
 use axum::{
     Json, Router,
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{Path, Query, State},
     routing::{get, post},
 };
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     AppState,
-    spotify::{Artist, Playlist, Spotify, Track},
+    api_tokens::{ApiUser, Scope},
+    error::ApiError,
+    roles::{self, Role},
+    routes::artists,
+    session::User,
+    spotify::{
+        AlbumSummary, Artist, CollectionSource, ExternalUrls, Image, Playlist, Spotify, Track,
+    },
 };
 
 #[derive(Debug, Serialize)]
@@ -22,6 +32,7 @@ pub struct Song {
     pub deviation: f64,
     pub volatility: f64,
     pub total_matches: i32,
+    pub is_playable: Option<bool>,
 }
 
 #[derive(Debug, Serialize)]
@@ -31,6 +42,16 @@ pub struct RatedTrack {
     pub name: String,
     pub artists: Vec<Artist>,
     pub image_url: Option<String>,
+    pub album: AlbumSummary,
+    pub images: Vec<Image>,
+    pub duration_ms: u32,
+    pub explicit: bool,
+    pub popularity: u32,
+    pub uri: String,
+    pub external_urls: ExternalUrls,
+    pub preview_url: Option<String>,
+    pub is_playable: Option<bool>,
+    pub restriction: Option<String>,
     pub rating: f64,
     pub deviation: f64,
     pub volatility: f64,
@@ -45,6 +66,16 @@ impl RatedTrack {
             name: track.name.clone(),
             artists: track.artists.clone(),
             image_url: track.image_url.clone(),
+            album: track.album.clone(),
+            images: track.images.clone(),
+            duration_ms: track.duration_ms,
+            explicit: track.explicit,
+            popularity: track.popularity,
+            uri: track.uri.clone(),
+            external_urls: track.external_urls.clone(),
+            preview_url: track.preview_url.clone(),
+            is_playable: track.is_playable,
+            restriction: track.restriction.clone(),
             rating: song.rating,
             deviation: song.deviation,
             volatility: song.volatility,
@@ -53,76 +84,160 @@ impl RatedTrack {
     }
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PlaylistFilter {
+    Owned,
+    Followed,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PlaylistSort {
+    Name,
+    Size,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaylistsQuery {
+    // Bypass the cached listing, e.g. after creating a playlist
+    #[serde(default)]
+    refresh: bool,
+    filter: Option<PlaylistFilter>,
+    // Spotify's order (most recently added first) is kept if not set
+    sort: Option<PlaylistSort>,
+}
+
 async fn get_playlists(
     State(state): State<AppState>,
-    mut spotify: Spotify,
-) -> Result<Json<Vec<Playlist>>, StatusCode> {
-    let playlists = spotify.get_playlists(&state).await.map_err(|e| {
-        tracing::error!("Failed to fetch playlists: {:#?}", e);
-        StatusCode::UNAUTHORIZED
-    })?;
+    user: ApiUser,
+    Query(query): Query<PlaylistsQuery>,
+) -> Result<Json<Vec<Playlist>>, ApiError> {
+    user.require(Scope::Read)?;
+    let mut spotify = state.spotify.for_user(user.id).await?;
+    let mut playlists = spotify.get_playlists(query.refresh).await?;
+
+    match query.filter {
+        Some(PlaylistFilter::Owned) => playlists.retain(|p| p.owner.id == user.spotify_id),
+        Some(PlaylistFilter::Followed) => playlists.retain(|p| p.owner.id != user.spotify_id),
+        None => {}
+    }
+
+    match query.sort {
+        Some(PlaylistSort::Name) => {
+            playlists.sort_by_cached_key(|p| p.name.to_lowercase());
+        }
+        Some(PlaylistSort::Size) => {
+            playlists.sort_by_key(|p| Reverse(p.total_tracks));
+        }
+        None => {}
+    }
+
     Ok(Json(playlists))
 }
 
-// Check that a playlist is in database, and if not then add it
+#[derive(Debug, Serialize)]
+pub struct SyncStatus {
+    // Key of the ranking session, which invited members need to open it
+    pub ranking_id: String,
+    pub snapshot_id: Option<String>,
+    pub last_synced: DateTime<Utc>,
+    pub changed: bool,
+    pub added: usize,
+    pub removed: usize,
+}
+
+// Check that a collection is in database, and if not then add it
 // This is basically starting a new "playlist rating session"
+// `playlist_id` is a collection source key, so this also works for liked songs, albums etc.
 async fn check_playlist(
     Path(playlist_id): Path<String>,
     State(state): State<AppState>,
-    mut spotify: Spotify,
-) -> Result<(), StatusCode> {
-    // let tracks = spotify
-    //     .get_playlist_tracks(&state, &playlist_id)
-    //     .await
-    //     .map_err(|err| {
-    //         tracing::error!("Failed to fetch playlist tracks: {:#?}", err);
-    //         StatusCode::UNAUTHORIZED
-    //     })?;
-    // Ok(Json(tracks))
+    user: User,
+) -> Result<Json<SyncStatus>, ApiError> {
+    let source = CollectionSource::from_key(&playlist_id, user.id)?;
+    let playlist_id = source.key();
+    // Liked songs and top tracks can only be read with their owner's account
+    if source
+        .owner_id()
+        .is_some_and(|owner_id| owner_id != user.id)
+    {
+        return Err(ApiError::Forbidden(
+            "Only the owner can sync their personal collections",
+        ));
+    }
+    let claiming = roles::require_or_unclaimed(&state.pool, &playlist_id, user.id, Role::Editor)
+        .await?
+        .is_none();
+    // Anyone can rank what they can read, but only in their own session
+    if claiming && source.user_id() != user.id {
+        return Err(ApiError::Forbidden(
+            "You don't have access to this ranking, ask its owner for an invite",
+        ));
+    }
+    let mut spotify = state.spotify.for_user(user.id).await?;
+
+    let status = sync_collection(&state.pool, &mut spotify, &source).await?;
+
+    // Only claimed once the sync worked, so a failed sync doesn't leave an empty session behind
+    if claiming {
+        roles::claim(&state.pool, &source, user.id, Role::Editor).await?;
+    }
+
+    Ok(Json(status))
+}
+
+async fn sync_collection(
+    pool: &sqlx::PgPool,
+    spotify: &mut Spotify,
+    source: &CollectionSource,
+) -> Result<SyncStatus, ApiError> {
+    let playlist_id = source.key();
+    let snapshot_id = spotify.get_source_snapshot_id(source).await?;
+
+    let stored = sqlx::query!(
+        "SELECT snapshot_id, last_synced FROM playlists WHERE playlist_id = $1",
+        playlist_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    // Nothing has changed since the last sync, so there is no need to touch the tracks
+    if let Some(stored) = stored
+        && snapshot_id.is_some()
+        && stored.snapshot_id == snapshot_id
+    {
+        return Ok(SyncStatus {
+            ranking_id: playlist_id,
+            snapshot_id,
+            last_synced: stored.last_synced,
+            changed: false,
+            added: 0,
+            removed: 0,
+        });
+    }
 
     // Get all current songs from database
-    let songs = sqlx::query_as!(
-        Song,
-        "SELECT id, song_id, playlist_id, rating, deviation, volatility, total_matches FROM songs WHERE playlist_id = $1",
+    let songs = sqlx::query!(
+        "SELECT id, song_id FROM songs WHERE playlist_id = $1",
         playlist_id
     )
-    .fetch_all(&state.pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to fetch songs: {:#?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    .fetch_all(pool)
+    .await?;
 
     let song_ids: HashSet<String> = HashSet::from_iter(songs.iter().map(|s| s.song_id.clone()));
 
-    // Fetch songs from spotify and compare for any changes/insert all new songs
-    let tracks = spotify
-        .get_playlist_tracks(&state, &playlist_id)
-        .await
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
-
+    // Fetch only the track ids from spotify and compare for any changes
+    let tracks = spotify.get_source_tracks(source).await?;
     let track_ids: HashSet<String> = HashSet::from_iter(tracks.iter().map(|t| t.id.clone()));
 
-    let mut new_song_ids = Vec::new();
-
-    for track in tracks {
-        // Check if the song is already in the database
-        if song_ids.contains(&track.id) {
-            continue;
-        }
-
-        // If not, insert the new song into the database
-        new_song_ids.push(track.id.clone());
-    }
-
-    let mut deleted_songs = Vec::new();
+    let new_song_ids: Vec<String> = track_ids.difference(&song_ids).cloned().collect();
 
-    // Check for any deleted songs
-    for song in songs {
-        if !track_ids.contains(&song.song_id) {
-            deleted_songs.push(song.id);
-        }
-    }
+    let deleted_songs: Vec<i32> = songs
+        .iter()
+        .filter(|song| !track_ids.contains(&song.song_id))
+        .map(|song| song.id)
+        .collect();
 
     tracing::info!(
         "New songs: {:?}, Deleted songs: {:?}",
@@ -139,50 +254,83 @@ async fn check_playlist(
         &new_song_ids,
         &playlist_ids,
     )
-    .execute(&state.pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to insert new songs: {:#?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    .execute(pool)
+    .await?;
 
     // Remove deleted songs from the database
     sqlx::query!(
         "DELETE FROM songs WHERE id = ANY($1::integer[])",
         &deleted_songs
     )
-    .execute(&state.pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to delete songs: {:#?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    .execute(pool)
+    .await?;
+
+    // Availability can change without the collection changing, so it's updated for every song
+    let ids: Vec<String> = tracks.iter().map(|t| t.id.clone()).collect();
+    let playable: Vec<Option<bool>> = tracks.iter().map(|t| t.is_playable).collect();
+    let restrictions: Vec<Option<String>> = tracks.iter().map(|t| t.restriction.clone()).collect();
+
+    sqlx::query!(
+        "UPDATE songs s SET is_playable = t.is_playable, restriction = t.restriction
+         FROM UNNEST($1::text[], $2::boolean[], $3::text[]) AS t(song_id, is_playable, restriction)
+         WHERE s.song_id = t.song_id AND s.playlist_id = $4",
+        &ids,
+        &playable as &[Option<bool>],
+        &restrictions as &[Option<String>],
+        playlist_id
+    )
+    .execute(pool)
+    .await?;
 
-    Ok(())
+    // Artist and genre data is only used for grouping, so a failure shouldn't fail the sync
+    let track_ids: Vec<String> = track_ids.into_iter().collect();
+    if let Err(e) = artists::enrich_songs(spotify, pool, &track_ids).await {
+        tracing::warn!("Failed to fetch artists: {}", e);
+    }
+
+    // Remember the snapshot so the next sync can be skipped if nothing changes
+    let last_synced = sqlx::query_scalar!(
+        "INSERT INTO playlists (playlist_id, snapshot_id, last_synced) VALUES ($1, $2, NOW())
+         ON CONFLICT (playlist_id)
+         DO UPDATE SET snapshot_id = EXCLUDED.snapshot_id, last_synced = EXCLUDED.last_synced
+         RETURNING last_synced",
+        playlist_id,
+        snapshot_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(SyncStatus {
+        ranking_id: playlist_id,
+        snapshot_id,
+        last_synced,
+        changed: true,
+        added: new_song_ids.len(),
+        removed: deleted_songs.len(),
+    })
 }
 
 pub async fn get_leaderboard(
     Path(playlist_id): Path<String>,
     State(state): State<AppState>,
-    mut spotify: Spotify,
-) -> Result<Json<Vec<RatedTrack>>, StatusCode> {
+    user: ApiUser,
+) -> Result<Json<Vec<RatedTrack>>, ApiError> {
+    user.require(Scope::Read)?;
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+    roles::require(&state.pool, &playlist_id, user.id, Role::Viewer).await?;
+    let mut spotify = state.spotify.for_user(user.id).await?;
+
     let songs = sqlx::query_as!(
         Song,
-        "SELECT id, song_id, playlist_id, rating, deviation, volatility, total_matches FROM songs WHERE playlist_id = $1 ORDER BY rating DESC LIMIT 10",
+        "SELECT id, song_id, playlist_id, rating, deviation, volatility, total_matches, is_playable FROM songs WHERE playlist_id = $1 ORDER BY rating DESC LIMIT 10",
         playlist_id
     )
     .fetch_all(&state.pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to fetch leaderboard: {:#?}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-
-    // Fetch songs from spotify
-    let spotify_tracks = spotify
-        .get_playlist_tracks(&state, &playlist_id)
-        .await
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    .await?;
+
+    // Only fetch the leaderboard songs from spotify, rather than the whole collection
+    let song_ids: Vec<String> = songs.iter().map(|song| song.song_id.clone()).collect();
+    let spotify_tracks = spotify.get_tracks(&song_ids).await?;
 
     // Map the songs to RatedTrack
     let songs: Vec<RatedTrack> = songs
@@ -198,9 +346,451 @@ pub async fn get_leaderboard(
     Ok(Json(songs))
 }
 
+#[derive(Debug, Deserialize)]
+struct ExportRequest {
+    name: Option<String>,
+    // Only export the top N songs, or every song if not set
+    limit: Option<i64>,
+    #[serde(default)]
+    public: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportResult {
+    pub playlist_id: String,
+    pub snapshot_id: String,
+    pub created: bool,
+    pub total: usize,
+}
+
+// Write the current ranking to a Spotify playlist, creating it on the first export
+async fn export_playlist(
+    Path(playlist_id): Path<String>,
+    State(state): State<AppState>,
+    user: ApiUser,
+    Json(request): Json<ExportRequest>,
+) -> Result<Json<ExportResult>, ApiError> {
+    user.require(Scope::Export)?;
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+    roles::require(&state.pool, &playlist_id, user.id, Role::Editor).await?;
+    let mut spotify = state.spotify.for_user(user.id).await?;
+
+    if request.limit.is_some_and(|limit| limit < 1) {
+        return Err(ApiError::BadRequest("Limit must be at least 1"));
+    }
+
+    let uris: Vec<String> = sqlx::query_scalar!(
+        "SELECT 'spotify:track:' || song_id FROM songs WHERE playlist_id = $1 ORDER BY rating DESC LIMIT $2",
+        playlist_id,
+        request.limit
+    )
+    .fetch_all(&state.pool)
+    .await?
+    .into_iter()
+    .flatten()
+    .collect();
+
+    if uris.is_empty() {
+        return Err(ApiError::BadRequest("There are no ranked songs to export"));
+    }
+
+    let existing = sqlx::query_scalar!(
+        "SELECT target_playlist_id FROM exported_playlists WHERE user_id = $1 AND playlist_id = $2",
+        user.id,
+        playlist_id
+    )
+    .fetch_optional(&state.pool)
+    .await?;
+
+    let created = existing.is_none();
+    let target_playlist_id = match existing {
+        Some(target_playlist_id) => target_playlist_id,
+        None => {
+            let name = request.name.as_deref().unwrap_or("Ranked playlist");
+            let target_playlist_id = spotify
+                .create_playlist(name, "Sorted by Spotify Rankings", request.public)
+                .await?;
+
+            sqlx::query!(
+                "INSERT INTO exported_playlists (user_id, playlist_id, target_playlist_id) VALUES ($1, $2, $3)",
+                user.id,
+                playlist_id,
+                target_playlist_id
+            )
+            .execute(&state.pool)
+            .await?;
+
+            target_playlist_id
+        }
+    };
+
+    let snapshot_id = spotify
+        .replace_playlist_tracks(&target_playlist_id, &uris)
+        .await?;
+
+    Ok(Json(ExportResult {
+        playlist_id: target_playlist_id,
+        snapshot_id,
+        created,
+        total: uris.len(),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ReorderRequest {
+    #[serde(default)]
+    dry_run: bool,
+    // Snapshot the preview was computed against, refuse to reorder if the playlist changed since
+    snapshot_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct ReorderMove {
+    pub range_start: usize,
+    pub insert_before: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReorderResult {
+    pub snapshot_id: String,
+    pub dry_run: bool,
+    pub moves: Vec<ReorderMove>,
+    // How many of the moves were made, in order, fewer than planned if one of them failed
+    pub applied: usize,
+    pub error: Option<String>,
+}
+
+// Every move is its own request to Spotify, larger reorders are better done with an export
+const MAX_REORDER_MOVES: usize = 200;
+
+// Sort the source playlist itself by rating, moving as few items as possible
+async fn reorder_playlist(
+    Path(playlist_id): Path<String>,
+    State(state): State<AppState>,
+    user: User,
+    Json(request): Json<ReorderRequest>,
+) -> Result<Json<ReorderResult>, ApiError> {
+    let source = CollectionSource::from_key(&playlist_id, user.id)?;
+    let CollectionSource::Playlist(spotify_playlist_id, _) = &source else {
+        return Err(ApiError::BadRequest("Only playlists can be reordered"));
+    };
+    let playlist_id = source.key();
+    roles::require(&state.pool, &playlist_id, user.id, Role::Editor).await?;
+    let mut spotify = state.spotify.for_user(user.id).await?;
+
+    let snapshot_id = spotify
+        .get_playlist_snapshot_id(spotify_playlist_id)
+        .await?;
+    if request
+        .snapshot_id
+        .as_ref()
+        .is_some_and(|expected| *expected != snapshot_id)
+    {
+        return Err(ApiError::Conflict(
+            "The playlist has changed since the preview",
+        ));
+    }
+
+    let items: Vec<Option<String>> = spotify
+        .get_playlist_items(spotify_playlist_id)
+        .await?
+        .into_iter()
+        .map(|item| item.map(|track| track.id))
+        .collect();
+
+    // Make sure the items belong to the snapshot the moves will be applied to
+    if spotify
+        .get_playlist_snapshot_id(spotify_playlist_id)
+        .await?
+        != snapshot_id
+    {
+        return Err(ApiError::Conflict("The playlist changed while reordering"));
+    }
+
+    let ratings: HashMap<String, f64> = sqlx::query!(
+        "SELECT song_id, rating FROM songs WHERE playlist_id = $1",
+        playlist_id
+    )
+    .fetch_all(&state.pool)
+    .await?
+    .into_iter()
+    .map(|song| (song.song_id, song.rating))
+    .collect();
+
+    let moves = plan_reorder(&items, &ratings);
+
+    if request.dry_run {
+        return Ok(Json(ReorderResult {
+            snapshot_id,
+            dry_run: true,
+            moves,
+            applied: 0,
+            error: None,
+        }));
+    }
+
+    if moves.len() > MAX_REORDER_MOVES {
+        return Err(ApiError::BadRequest(
+            "Too many moves to reorder the playlist in place, export a sorted copy instead",
+        ));
+    }
+
+    let mut snapshot_id = snapshot_id;
+    let mut applied = 0;
+    let mut error = None;
+    for mv in &moves {
+        match spotify
+            .reorder_playlist_track(
+                spotify_playlist_id,
+                mv.range_start,
+                mv.insert_before,
+                &snapshot_id,
+            )
+            .await
+        {
+            Ok(new_snapshot_id) => {
+                snapshot_id = new_snapshot_id;
+                applied += 1;
+            }
+            // Nothing has changed yet, so this is an ordinary failure
+            Err(e) if applied == 0 => return Err(e),
+            // The playlist is partly reordered now, so report how far it got
+            Err(e) => {
+                tracing::warn!(
+                    "Reordering {} failed after {} of {} moves: {}",
+                    playlist_id,
+                    applied,
+                    moves.len(),
+                    e
+                );
+                error = Some(e.to_string());
+                break;
+            }
+        }
+    }
+
+    Ok(Json(ReorderResult {
+        snapshot_id,
+        dry_run: false,
+        moves,
+        applied,
+        error,
+    }))
+}
+
+// Computes the fewest single item moves that sort the playlist by rating
+// Items on the longest increasing subsequence of target positions stay where they are, and every
+// other item is moved exactly once, which is the minimum number of moves possible
+fn plan_reorder(items: &[Option<String>], ratings: &HashMap<String, f64>) -> Vec<ReorderMove> {
+    let rating = |idx: usize| items[idx].as_ref().and_then(|id| ratings.get(id)).copied();
+
+    // Unranked items go to the end, and ties keep their current order since the sort is stable
+    let mut order: Vec<usize> = (0..items.len()).collect();
+    order.sort_by(|&a, &b| match (rating(a), rating(b)) {
+        (Some(a), Some(b)) => b.total_cmp(&a),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    });
+
+    // Target position of the item currently at each position
+    let mut current = vec![0; items.len()];
+    for (target, &idx) in order.iter().enumerate() {
+        current[idx] = target;
+    }
+
+    let mut placed = vec![false; items.len()];
+    for target in longest_increasing_subsequence(&current) {
+        placed[target] = true;
+    }
+
+    let mut moves = Vec::new();
+    for target in 0..items.len() {
+        if placed[target] {
+            continue;
+        }
+
+        let from = current.iter().position(|&t| t == target).unwrap();
+        // Placed items are always in order, so insert before the first one that should follow
+        let insert_before = current
+            .iter()
+            .position(|&t| placed[t] && t > target)
+            .unwrap_or(current.len());
+
+        current.remove(from);
+        let to = if insert_before > from {
+            insert_before - 1
+        } else {
+            insert_before
+        };
+        current.insert(to, target);
+        placed[target] = true;
+
+        moves.push(ReorderMove {
+            range_start: from,
+            insert_before,
+        });
+    }
+
+    moves
+}
+
+fn longest_increasing_subsequence(values: &[usize]) -> Vec<usize> {
+    // Index of the smallest tail of an increasing subsequence of each length
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous = vec![None; values.len()];
+
+    for (idx, &value) in values.iter().enumerate() {
+        let len = tails.partition_point(|&tail| values[tail] < value);
+        if len > 0 {
+            previous[idx] = Some(tails[len - 1]);
+        }
+        if len == tails.len() {
+            tails.push(idx);
+        } else {
+            tails[len] = idx;
+        }
+    }
+
+    let mut subsequence = Vec::new();
+    let mut next = tails.last().copied();
+    while let Some(idx) = next {
+        subsequence.push(values[idx]);
+        next = previous[idx];
+    }
+
+    subsequence
+}
+
 pub fn get_router() -> Router<AppState> {
     Router::new()
         .route("/playlists", get(get_playlists))
         .route("/playlists/{playlist_id}", post(check_playlist))
         .route("/playlists/{playlist_id}/leaderboard", get(get_leaderboard))
+        .route("/playlists/{playlist_id}/export", post(export_playlist))
+        .route("/playlists/{playlist_id}/reorder", post(reorder_playlist))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(ids: &[Option<&str>]) -> Vec<Option<String>> {
+        ids.iter().map(|id| id.map(str::to_string)).collect()
+    }
+
+    fn ratings(ratings: &[(&str, f64)]) -> HashMap<String, f64> {
+        ratings
+            .iter()
+            .map(|&(id, rating)| (id.to_string(), rating))
+            .collect()
+    }
+
+    // Applies the moves the way Spotify does, `insert_before` refers to the list before the move
+    fn apply(items: &[Option<String>], moves: &[ReorderMove]) -> Vec<Option<String>> {
+        let mut items = items.to_vec();
+        for mv in moves {
+            let item = items.remove(mv.range_start);
+            let to = if mv.insert_before > mv.range_start {
+                mv.insert_before - 1
+            } else {
+                mv.insert_before
+            };
+            items.insert(to, item);
+        }
+        items
+    }
+
+    #[test]
+    fn sorted_playlist_needs_no_moves() {
+        let items = items(&[Some("a"), Some("b"), Some("c")]);
+        let ratings = ratings(&[("a", 1700.0), ("b", 1600.0), ("c", 1500.0)]);
+
+        assert!(plan_reorder(&items, &ratings).is_empty());
+    }
+
+    #[test]
+    fn reversed_playlist_moves_all_but_one() {
+        let items = items(&[Some("a"), Some("b"), Some("c"), Some("d")]);
+        let ratings = ratings(&[("a", 1400.0), ("b", 1500.0), ("c", 1600.0), ("d", 1700.0)]);
+
+        let moves = plan_reorder(&items, &ratings);
+        assert_eq!(moves.len(), 3);
+        assert_eq!(
+            apply(&items, &moves),
+            self::items(&[Some("d"), Some("c"), Some("b"), Some("a")])
+        );
+    }
+
+    #[test]
+    fn unranked_and_local_tracks_stay_at_the_end() {
+        // `None` is a local track, "x" was never rated
+        let items = items(&[Some("b"), Some("a"), None, Some("x")]);
+        let ratings = ratings(&[("a", 1600.0), ("b", 1500.0)]);
+
+        let moves = plan_reorder(&items, &ratings);
+        assert_eq!(moves.len(), 1);
+        assert_eq!(
+            apply(&items, &moves),
+            self::items(&[Some("a"), Some("b"), None, Some("x")])
+        );
+    }
+
+    #[test]
+    fn unranked_tracks_keep_their_order() {
+        let items = items(&[Some("x"), Some("a"), None, Some("y"), Some("b")]);
+        let ratings = ratings(&[("a", 1500.0), ("b", 1600.0)]);
+
+        assert_eq!(
+            apply(&items, &plan_reorder(&items, &ratings)),
+            self::items(&[Some("b"), Some("a"), Some("x"), None, Some("y")])
+        );
+    }
+
+    #[test]
+    fn duplicate_tracks_are_both_moved() {
+        let items = items(&[Some("a"), Some("b"), Some("a"), Some("c")]);
+        let ratings = ratings(&[("a", 1700.0), ("b", 1500.0), ("c", 1600.0)]);
+
+        assert_eq!(
+            apply(&items, &plan_reorder(&items, &ratings)),
+            self::items(&[Some("a"), Some("a"), Some("c"), Some("b")])
+        );
+    }
+
+    #[test]
+    fn plan_produces_the_target_order() {
+        let ids = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let items: Vec<Option<String>> = ids.iter().map(|id| Some(id.to_string())).collect();
+        let ratings = ratings(&[
+            ("a", 1510.0),
+            ("b", 1790.0),
+            ("c", 1320.0),
+            ("d", 1650.0),
+            ("e", 1480.0),
+            ("f", 1900.0),
+            ("g", 1550.0),
+            ("h", 1400.0),
+        ]);
+
+        let mut expected = items.clone();
+        expected
+            .sort_by(|a, b| ratings[b.as_ref().unwrap()].total_cmp(&ratings[a.as_ref().unwrap()]));
+
+        let moves = plan_reorder(&items, &ratings);
+        assert_eq!(apply(&items, &moves), expected);
+        // Only the items off the longest run already in order are moved
+        assert_eq!(moves.len(), ids.len() - 4);
+    }
+
+    #[test]
+    fn longest_increasing_subsequence_of_positions() {
+        assert_eq!(longest_increasing_subsequence(&[]), Vec::<usize>::new());
+        assert_eq!(longest_increasing_subsequence(&[0, 1, 2]).len(), 3);
+        assert_eq!(longest_increasing_subsequence(&[2, 1, 0]).len(), 1);
+
+        let mut subsequence = longest_increasing_subsequence(&[3, 0, 4, 1, 2, 5]);
+        subsequence.reverse();
+        assert_eq!(subsequence, vec![0, 1, 2, 5]);
+    }
 }
@@ -0,0 +1,502 @@
+use axum::{
+    Json, Router,
+    extract::{FromRequestParts, Path, Query, State},
+    routing::{get, patch, post},
+};
+use axum_extra::extract::{CookieJar, cookie::Cookie};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    error::ApiError,
+    roles::{self, Role},
+    routes::matchmaking::{self, Match, MatchResult, MatchmakingQuery},
+    session::{self, User},
+    spotify::CollectionSource,
+};
+
+const GUEST_COOKIE: &str = "guest_token";
+
+// Guest sessions end after this long, or earlier if their link expires
+const GUEST_SESSION_DAYS: i32 = 7;
+
+// Guests use the owner's Spotify quota, so they get a fixed number of requests per window
+const GUEST_RATE_WINDOW_SECS: i64 = 60;
+const GUEST_REQUESTS_PER_WINDOW: i32 = 30;
+
+// Shared by all guests of a link, so opening more guest sessions doesn't raise the load
+const LINK_REQUESTS_PER_WINDOW: i32 = 120;
+
+// Limits how fast guest sessions can be created for a single link
+const JOINS_PER_WINDOW: i64 = 10;
+
+#[derive(Debug, Deserialize)]
+struct CreateShare {
+    vote_weight: Option<f64>,
+    // Links without an expiry work until they are revoked
+    expires_in_days: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+struct ShareLink {
+    id: i32,
+    // Only ever shown here, the database only has its hash
+    token: String,
+    url: String,
+    vote_weight: f64,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ShareInfo {
+    id: i32,
+    // Spotify id of the editor who created the link, None once their account is deleted
+    created_by: Option<String>,
+    vote_weight: f64,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+    guests: i64,
+    votes: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateShare {
+    vote_weight: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinRequest {
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct GuestInfo {
+    playlist_id: String,
+    name: Option<String>,
+}
+
+fn validate_weight(weight: f64) -> Result<f64, ApiError> {
+    if !(0.0..=1.0).contains(&weight) {
+        return Err(ApiError::BadRequest("Vote weight must be between 0 and 1"));
+    }
+
+    Ok(weight)
+}
+
+/// A guest who joined through a share link, resolved from the `guest_token` cookie. Every
+/// extraction counts towards the rate limits of both the guest and the link.
+pub struct Guest {
+    pub session_id: i32,
+    pub share_link_id: i32,
+    // Whoever created the link, an editor of the ranking at least
+    pub creator_id: i32,
+    pub playlist_id: String,
+    pub vote_weight: f64,
+}
+
+impl<S> FromRequestParts<S> for Guest
+where
+    S: Send + Sync,
+    AppState: axum::extract::FromRef<S>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let State(app_state): State<AppState> =
+            State::from_request_parts(parts, state).await.unwrap();
+
+        let jar = CookieJar::from_request_parts(parts, state)
+            .await
+            .map_err(|_| ApiError::BadRequest("Failed to extract guest cookie"))?;
+
+        let guest_token = jar
+            .get(GUEST_COOKIE)
+            .ok_or(ApiError::BadRequest("Guest token not found"))?
+            .value();
+
+        // Both windows start over once the previous one has passed
+        let guest = sqlx::query!(
+            r#"WITH guest AS (
+                 UPDATE guest_sessions g
+                 SET window_start = CASE WHEN g.window_start <= NOW() - make_interval(secs => $2) THEN NOW() ELSE g.window_start END,
+                     window_requests = CASE WHEN g.window_start <= NOW() - make_interval(secs => $2) THEN 1 ELSE g.window_requests + 1 END
+                 FROM share_links l
+                 WHERE l.id = g.share_link_id AND g.token_hash = $1 AND g.expires_at > NOW()
+                   AND l.revoked_at IS NULL AND (l.expires_at IS NULL OR l.expires_at > NOW())
+                 RETURNING g.id, g.share_link_id, g.window_requests,
+                    EXTRACT(EPOCH FROM g.window_start + make_interval(secs => $2) - NOW())::float8 AS window_left
+             ), link AS (
+                 UPDATE share_links l
+                 SET window_start = CASE WHEN l.window_start <= NOW() - make_interval(secs => $2) THEN NOW() ELSE l.window_start END,
+                     window_requests = CASE WHEN l.window_start <= NOW() - make_interval(secs => $2) THEN 1 ELSE l.window_requests + 1 END
+                 FROM guest
+                 WHERE l.id = guest.share_link_id
+                 RETURNING l.id, l.user_id, l.playlist_id, l.vote_weight, l.window_requests,
+                    EXTRACT(EPOCH FROM l.window_start + make_interval(secs => $2) - NOW())::float8 AS window_left
+             )
+             SELECT guest.id AS "id!", guest.share_link_id AS "share_link_id!", link.user_id AS "user_id!",
+                link.playlist_id AS "playlist_id!", link.vote_weight AS "vote_weight!",
+                guest.window_requests AS "guest_requests!", guest.window_left AS "guest_window_left!",
+                link.window_requests AS "link_requests!", link.window_left AS "link_window_left!"
+             FROM guest JOIN link ON link.id = guest.share_link_id"#,
+            session::hash_token(guest_token),
+            GUEST_RATE_WINDOW_SECS as f64
+        )
+        .fetch_optional(&app_state.pool)
+        .await?
+        .ok_or(ApiError::Unauthorized("Invalid guest token"))?;
+
+        if guest.guest_requests > GUEST_REQUESTS_PER_WINDOW {
+            return Err(ApiError::RateLimited {
+                retry_after: guest.guest_window_left.ceil().max(1.0) as u64,
+            });
+        }
+
+        if guest.link_requests > LINK_REQUESTS_PER_WINDOW {
+            return Err(ApiError::RateLimited {
+                retry_after: guest.link_window_left.ceil().max(1.0) as u64,
+            });
+        }
+
+        Ok(Guest {
+            session_id: guest.id,
+            share_link_id: guest.share_link_id,
+            creator_id: guest.user_id,
+            playlist_id: guest.playlist_id,
+            vote_weight: guest.vote_weight,
+        })
+    }
+}
+
+// Create a link that lets anyone with it vote on the collection
+async fn create_share(
+    State(state): State<AppState>,
+    Path(playlist_id): Path<String>,
+    user: User,
+    Json(request): Json<CreateShare>,
+) -> Result<Json<ShareLink>, ApiError> {
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+    roles::require(&state.pool, &playlist_id, user.id, Role::Editor).await?;
+    let vote_weight = validate_weight(request.vote_weight.unwrap_or(1.0))?;
+
+    if request.expires_in_days.is_some_and(|days| days <= 0) {
+        return Err(ApiError::BadRequest("Link expiry must be in the future"));
+    }
+
+    // Guests can only vote on songs that are already in the database
+    let synced = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM playlists WHERE playlist_id = $1) AS "synced!""#,
+        playlist_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    if !synced {
+        return Err(ApiError::BadRequest("Sync the playlist before sharing it"));
+    }
+
+    let token = session::generate_token();
+
+    let created = sqlx::query!(
+        "INSERT INTO share_links (user_id, playlist_id, token_hash, vote_weight, expires_at)
+         VALUES ($1, $2, $3, $4, NOW() + make_interval(days => $5))
+         RETURNING id, expires_at",
+        user.id,
+        playlist_id,
+        session::hash_token(&token),
+        vote_weight,
+        request.expires_in_days
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    let url = state
+        .frontend_url
+        .join(&format!("share/{}", token))
+        .map_err(|_| ApiError::BadRequest("Invalid share link"))?;
+
+    Ok(Json(ShareLink {
+        id: created.id,
+        token,
+        url: url.to_string(),
+        vote_weight,
+        expires_at: created.expires_at,
+    }))
+}
+
+// Editors see the links they created, owners see every link of the ranking
+async fn list_shares(
+    State(state): State<AppState>,
+    Path(playlist_id): Path<String>,
+    user: User,
+) -> Result<Json<Vec<ShareInfo>>, ApiError> {
+    let playlist_id = CollectionSource::from_key(&playlist_id, user.id)?.key();
+    let role = roles::require(&state.pool, &playlist_id, user.id, Role::Editor).await?;
+
+    let shares = sqlx::query_as!(
+        ShareInfo,
+        r#"SELECT l.id, u.spotify_id AS created_by, l.vote_weight, l.created_at, l.expires_at, l.revoked_at,
+                (SELECT COUNT(*) FROM guest_sessions WHERE share_link_id = l.id) AS "guests!",
+                (SELECT COUNT(*) FROM guest_votes WHERE share_link_id = l.id) AS "votes!"
+         FROM share_links l
+         LEFT JOIN users u ON u.id = l.user_id
+         WHERE l.playlist_id = $2 AND (l.user_id = $1 OR $3)
+         ORDER BY l.created_at"#,
+        user.id,
+        playlist_id,
+        role == Role::Owner
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(shares))
+}
+
+// Change how much the votes cast through a link count, 0 excludes them from the ratings.
+// Votes that were already cast count with the new weight too, so the ratings are rebuilt.
+async fn update_share(
+    State(state): State<AppState>,
+    Path(share_id): Path<i32>,
+    user: User,
+    Json(request): Json<UpdateShare>,
+) -> Result<(), ApiError> {
+    let vote_weight = validate_weight(request.vote_weight)?;
+    let playlist_id = manageable_link(&state.pool, share_id, user.id).await?;
+    let mut tx = state.pool.begin().await?;
+
+    sqlx::query!(
+        "UPDATE share_links SET vote_weight = $1 WHERE id = $2",
+        vote_weight,
+        share_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE guest_votes SET weight = $1 WHERE share_link_id = $2",
+        vote_weight,
+        share_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    matchmaking::replay_ratings(&mut tx, &playlist_id).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+// Stops the link from working and ends its guest sessions, the votes are kept
+async fn revoke_share(
+    State(state): State<AppState>,
+    Path(share_id): Path<i32>,
+    user: User,
+) -> Result<(), ApiError> {
+    manageable_link(&state.pool, share_id, user.id).await?;
+
+    let result = sqlx::query!(
+        "UPDATE share_links SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL",
+        share_id
+    )
+    .execute(&state.pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Share link not found"));
+    }
+
+    sqlx::query!(
+        "DELETE FROM guest_sessions WHERE share_link_id = $1",
+        share_id
+    )
+    .execute(&state.pool)
+    .await?;
+
+    Ok(())
+}
+
+// Links can be managed by whoever created them and by the owners of their ranking, returns the
+// ranking the link belongs to
+async fn manageable_link(
+    pool: &sqlx::PgPool,
+    share_id: i32,
+    user_id: i32,
+) -> Result<String, ApiError> {
+    let link = sqlx::query!(
+        "SELECT user_id, playlist_id FROM share_links WHERE id = $1",
+        share_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(ApiError::NotFound("Share link not found"))?;
+
+    if link.user_id != Some(user_id)
+        && roles::role_of(pool, &link.playlist_id, user_id).await? != Some(Role::Owner)
+    {
+        return Err(ApiError::NotFound("Share link not found"));
+    }
+
+    Ok(link.playlist_id)
+}
+
+/// Revokes the links a member created for a ranking and ends their guest sessions, for when the
+/// member can no longer share it. Guests would otherwise keep using the member's Spotify account.
+pub async fn revoke_links_of(
+    pool: &sqlx::PgPool,
+    playlist_id: &str,
+    user_id: i32,
+) -> Result<(), ApiError> {
+    let revoked = sqlx::query_scalar!(
+        "UPDATE share_links SET revoked_at = NOW()
+         WHERE playlist_id = $1 AND user_id = $2 AND revoked_at IS NULL
+         RETURNING id",
+        playlist_id,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM guest_sessions WHERE share_link_id = ANY($1)",
+        &revoked
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+// Start a guest session from a share link, no Spotify account needed
+async fn join_share(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    jar: CookieJar,
+    Json(request): Json<JoinRequest>,
+) -> Result<(CookieJar, Json<GuestInfo>), ApiError> {
+    let mut tx = state.pool.begin().await?;
+
+    // Locking the link makes concurrent joins wait, so they can't all pass the limit at once
+    let link = sqlx::query!(
+        "SELECT id, playlist_id FROM share_links
+         WHERE token_hash = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > NOW())
+         FOR UPDATE",
+        session::hash_token(&token)
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ApiError::NotFound("Share link not found"))?;
+
+    let recent_joins = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM guest_sessions
+         WHERE share_link_id = $1 AND created_at > NOW() - make_interval(secs => $2)"#,
+        link.id,
+        GUEST_RATE_WINDOW_SECS as f64
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if recent_joins >= JOINS_PER_WINDOW {
+        return Err(ApiError::RateLimited {
+            retry_after: GUEST_RATE_WINDOW_SECS as u64,
+        });
+    }
+
+    let name = request
+        .name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+    let guest_token = session::generate_token();
+
+    // LEAST ignores a missing link expiry
+    sqlx::query!(
+        "INSERT INTO guest_sessions (share_link_id, token_hash, name, expires_at)
+         SELECT $1, $2, $3, LEAST(NOW() + make_interval(days => $4), expires_at) FROM share_links WHERE id = $1",
+        link.id,
+        session::hash_token(&guest_token),
+        name,
+        GUEST_SESSION_DAYS
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let jar = jar.add(
+        Cookie::build((GUEST_COOKIE, guest_token))
+            .path("/")
+            .secure(true)
+            .http_only(true)
+            .max_age(time::Duration::days(GUEST_SESSION_DAYS.into())),
+    );
+
+    Ok((
+        jar,
+        Json(GuestInfo {
+            playlist_id: link.playlist_id,
+            name,
+        }),
+    ))
+}
+
+// Matches for guests are loaded with the Spotify account of the link's creator
+async fn guest_matchmaking(
+    State(state): State<AppState>,
+    Query(query): Query<MatchmakingQuery>,
+    guest: Guest,
+) -> Result<Json<Match>, ApiError> {
+    let found =
+        matchmaking::find_match(&state, guest.creator_id, &guest.playlist_id, &query).await?;
+
+    Ok(Json(found))
+}
+
+async fn guest_matchmaking_result(
+    State(state): State<AppState>,
+    guest: Guest,
+    Json(result): Json<MatchResult>,
+) -> Result<(), ApiError> {
+    let mut tx = state.pool.begin().await?;
+    matchmaking::rate_match(&mut tx, &guest.playlist_id, &result, guest.vote_weight).await?;
+
+    sqlx::query!(
+        "INSERT INTO guest_votes (share_link_id, guest_session_id, playlist_id, song_a, song_b, winner, weight)
+         VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        guest.share_link_id,
+        guest.session_id,
+        guest.playlist_id,
+        result.song_a,
+        result.song_b,
+        result.winner,
+        guest.vote_weight
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub fn get_router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/playlists/{playlist_id}/shares",
+            get(list_shares).post(create_share),
+        )
+        .route(
+            "/shares/{share_id}",
+            patch(update_share).delete(revoke_share),
+        )
+        .route("/share/{token}/join", post(join_share))
+        .route(
+            "/guest/matchmaking",
+            get(guest_matchmaking).post(guest_matchmaking_result),
+        )
+}
@@ -0,0 +1,128 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    routing::{delete, get},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    api_tokens::{self, Scope},
+    error::ApiError,
+    session::{self, User},
+};
+
+#[derive(Debug, Deserialize)]
+struct CreateToken {
+    name: String,
+    scopes: Vec<Scope>,
+    // Tokens without an expiry work until they are revoked
+    expires_in_days: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedToken {
+    id: i32,
+    name: String,
+    scopes: Vec<Scope>,
+    // Only ever shown here, the database only has its hash
+    token: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenInfo {
+    id: i32,
+    name: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+// Tokens are managed with the session cookie only, so a token can't be used to mint new ones
+async fn create_token(
+    State(state): State<AppState>,
+    user: User,
+    Json(request): Json<CreateToken>,
+) -> Result<Json<CreatedToken>, ApiError> {
+    if request.name.trim().is_empty() {
+        return Err(ApiError::BadRequest("Token name can't be empty"));
+    }
+    if request.scopes.is_empty() {
+        return Err(ApiError::BadRequest("Token needs at least one scope"));
+    }
+    if request.expires_in_days.is_some_and(|days| days <= 0) {
+        return Err(ApiError::BadRequest("Token expiry must be in the future"));
+    }
+
+    let token = api_tokens::generate_token();
+    let scopes: Vec<String> = request
+        .scopes
+        .iter()
+        .map(|scope| scope.as_str().to_string())
+        .collect();
+
+    let created = sqlx::query!(
+        "INSERT INTO api_tokens (user_id, name, token_hash, scopes, expires_at)
+         VALUES ($1, $2, $3, $4, NOW() + make_interval(days => $5))
+         RETURNING id, expires_at",
+        user.id,
+        request.name.trim(),
+        session::hash_token(&token),
+        &scopes,
+        request.expires_in_days
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    Ok(Json(CreatedToken {
+        id: created.id,
+        name: request.name.trim().to_string(),
+        scopes: request.scopes,
+        token,
+        expires_at: created.expires_at,
+    }))
+}
+
+async fn list_tokens(
+    State(state): State<AppState>,
+    user: User,
+) -> Result<Json<Vec<TokenInfo>>, ApiError> {
+    let tokens = sqlx::query_as!(
+        TokenInfo,
+        "SELECT id, name, scopes, created_at, last_used_at, expires_at FROM api_tokens WHERE user_id = $1 ORDER BY created_at",
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(tokens))
+}
+
+async fn revoke_token(
+    State(state): State<AppState>,
+    Path(token_id): Path<i32>,
+    user: User,
+) -> Result<(), ApiError> {
+    let result = sqlx::query!(
+        "DELETE FROM api_tokens WHERE id = $1 AND user_id = $2",
+        token_id,
+        user.id
+    )
+    .execute(&state.pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Token not found"));
+    }
+
+    Ok(())
+}
+
+pub fn get_router() -> Router<AppState> {
+    Router::new()
+        .route("/me/tokens", get(list_tokens).post(create_token))
+        .route("/me/tokens/{token_id}", delete(revoke_token))
+}
@@ -0,0 +1,166 @@
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{CookieJar, cookie::Cookie};
+use sha2::{Digest, Sha256};
+
+use crate::{AppState, error::ApiError};
+
+pub const SESSION_COOKIE: &str = "session_token";
+
+// Sessions expire after this long without being used
+pub const SESSION_DAYS: i32 = 30;
+
+// How often the token of an active session is replaced
+const ROTATE_AFTER_HOURS: i32 = 24;
+
+// How long the previous token keeps working after a rotation
+const ROTATION_GRACE_SECS: f64 = 60.0;
+
+/// A new random 256-bit session token, only its hash should be stored
+pub fn generate_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Sessions are looked up by this hash, so leaked rows can't be used as cookies
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// The session cookie, which lives as long as the session does
+pub fn cookie(token: String) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .max_age(time::Duration::days(SESSION_DAYS.into()))
+        .build()
+}
+
+/// Removes the session cookie from the browser
+pub fn removal_cookie() -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE).path("/").build()
+}
+
+/// The logged in user, resolved from the `session_token` cookie
+pub struct User {
+    pub id: i32,
+    pub spotify_id: String,
+    pub session_id: i32,
+}
+
+impl<S> FromRequestParts<S> for User
+where
+    S: Send + Sync,
+    AppState: axum::extract::FromRef<S>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let State(app_state): State<AppState> =
+            State::from_request_parts(parts, state).await.unwrap();
+
+        let jar = CookieJar::from_request_parts(parts, state)
+            .await
+            .map_err(|_| ApiError::BadRequest("Failed to extract session cookie"))?;
+
+        let session_token = jar
+            .get(SESSION_COOKIE)
+            .ok_or(ApiError::BadRequest("Session token not found"))?
+            .value();
+
+        let row = sqlx::query!(
+            "SELECT u.id, u.spotify_id, u.needs_reauth, s.id AS session_id
+             FROM users u
+             JOIN sessions s ON u.id = s.user_id
+             WHERE s.expires_at > NOW()
+               AND (s.token_hash = $1
+                    OR (s.previous_token_hash = $1 AND s.rotated_at > NOW() - make_interval(secs => $2)))",
+            hash_token(session_token),
+            ROTATION_GRACE_SECS
+        )
+        .fetch_optional(&app_state.pool)
+        .await?
+        .ok_or(ApiError::Unauthorized("Invalid token"))?;
+
+        // Spotify revoked our tokens, so nothing can be done until the user logs in again
+        if row.needs_reauth {
+            return Err(ApiError::ReauthRequired);
+        }
+
+        Ok(User {
+            id: row.id,
+            spotify_id: row.spotify_id,
+            session_id: row.session_id,
+        })
+    }
+}
+
+/// Middleware that replaces the token of an active session once a day, which also slides its
+/// expiry forward. Runs after the handler, so logging out or in isn't undone.
+pub async fn rotate(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+
+    let Some(session_token) = jar.get(SESSION_COOKIE) else {
+        return response;
+    };
+
+    // The handler already replaced or removed the session cookie
+    let prefix = format!("{}=", SESSION_COOKIE);
+    if response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .any(|value| value.as_bytes().starts_with(prefix.as_bytes()))
+    {
+        return response;
+    }
+
+    let token = generate_token();
+    let rotated = sqlx::query_scalar!(
+        "UPDATE sessions
+         SET previous_token_hash = token_hash, token_hash = $2, rotated_at = NOW(), expires_at = NOW() + make_interval(days => $3)
+         WHERE token_hash = $1 AND expires_at > NOW() AND rotated_at < NOW() - make_interval(hours => $4)
+         RETURNING id",
+        hash_token(session_token.value()),
+        hash_token(&token),
+        SESSION_DAYS,
+        ROTATE_AFTER_HOURS
+    )
+    .fetch_optional(&state.pool)
+    .await;
+
+    match rotated {
+        Ok(Some(_)) => (jar.add(cookie(token)), response).into_response(),
+        Ok(None) => response,
+        Err(e) => {
+            tracing::error!("Failed to rotate session: {}", e);
+            response
+        }
+    }
+}
+
+/// Deletes expired user and guest sessions, returning how many were removed
+pub async fn purge_expired(pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
+    let sessions = sqlx::query!("DELETE FROM sessions WHERE expires_at <= NOW()")
+        .execute(pool)
+        .await?;
+
+    // Their votes are kept, only the link to the session is cleared
+    let guest_sessions = sqlx::query!("DELETE FROM guest_sessions WHERE expires_at <= NOW()")
+        .execute(pool)
+        .await?;
+
+    Ok(sessions.rows_affected() + guest_sessions.rows_affected())
+}
@@ -1,12 +1,28 @@
-use axum::{
-    extract::{FromRequestParts, State},
-    http::StatusCode,
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use axum_extra::extract::CookieJar;
+
+use axum::http::{Method, StatusCode, header};
 use chrono::Utc;
+use futures::{StreamExt, TryStreamExt, stream};
+use oauth2::{
+    AuthUrl, ClientId, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
+    basic::BasicClient, url::Url,
+};
 use serde::{Deserialize, Serialize};
 
-use crate::AppState;
+use crate::{crypto::Keyring, error::ApiError};
+
+// Maximum page sizes allowed by the Spotify API
+const PLAYLISTS_PAGE_LIMIT: usize = 50;
+const TRACKS_PAGE_LIMIT: usize = 100;
+const PLAYLIST_ITEMS_BATCH: usize = 100;
+
+// Cached playlist listings are dropped after this long, and the oldest ones once there are too many
+const PLAYLIST_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+const PLAYLIST_CACHE_MAX_USERS: usize = 1000;
 
 #[derive(Debug, Deserialize)]
 pub struct SpotifyResponse {
@@ -18,26 +34,59 @@ pub struct SpotifyResponse {
 #[derive(Debug, Deserialize)]
 struct SpotifyProfile {
     id: String,
+    display_name: Option<String>,
+    #[serde(default)]
+    images: Option<Vec<Image>>,
+    // Only returned with the `user-read-private` scope
+    country: Option<String>,
+    product: Option<String>,
+}
+
+impl SpotifyProfile {
+    fn avatar_url(&self) -> Option<&str> {
+        self.images
+            .as_ref()
+            .and_then(|images| images.first())
+            .map(|image| image.url.as_str())
+    }
 }
 
+/// A Spotify API handle acting on behalf of a single user
 pub struct Spotify {
+    client: SpotifyClient,
+    /// The user's country, used as the market for track queries
+    pub market: Option<String>,
     pub access_token: String,
     pub refresh_token: String,
     pub expires_at: chrono::DateTime<Utc>,
     pub spotify_id: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Playlist {
     pub href: String,
     pub id: String,
     pub name: String,
     pub image_url: String,
+    pub owner: PlaylistOwner,
+    pub collaborative: bool,
+    pub public: Option<bool>,
+    pub total_tracks: usize,
+    pub description: Option<String>,
+    pub snapshot_id: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct Image {
-    url: String,
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PlaylistOwner {
+    pub id: String,
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Image {
+    pub url: String,
+    pub height: Option<u32>,
+    pub width: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -45,7 +94,20 @@ struct PlaylistResponse {
     href: String,
     id: String,
     name: String,
-    images: Vec<Image>,
+    // Spotify returns null instead of an empty list for playlists without images
+    #[serde(default)]
+    images: Option<Vec<Image>>,
+    owner: PlaylistOwner,
+    collaborative: bool,
+    public: Option<bool>,
+    tracks: PlaylistTracksSummary,
+    description: Option<String>,
+    snapshot_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaylistTracksSummary {
+    total: usize,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -55,16 +117,116 @@ pub struct Track {
     pub name: String,
     pub artists: Vec<Artist>,
     pub image_url: Option<String>,
+    pub album: AlbumSummary,
+    pub images: Vec<Image>,
+    pub duration_ms: u32,
+    pub explicit: bool,
+    pub popularity: u32,
+    pub uri: String,
+    pub external_urls: ExternalUrls,
+    pub preview_url: Option<String>,
+    // Only known when the track was requested with a market
+    pub is_playable: Option<bool>,
+    pub restriction: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AlbumSummary {
+    pub id: String,
+    pub name: String,
+    pub release_date: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ExternalUrls {
+    pub spotify: Option<String>,
+}
+
+impl From<PlaylistResponse> for Playlist {
+    fn from(item: PlaylistResponse) -> Self {
+        Self {
+            href: item.href,
+            id: item.id,
+            name: item.name,
+            image_url: item
+                .images
+                .into_iter()
+                .flatten()
+                .next()
+                .map(|img| img.url)
+                .unwrap_or_default(),
+            owner: item.owner,
+            collaborative: item.collaborative,
+            public: item.public,
+            total_tracks: item.tracks.total,
+            // Spotify returns an empty string rather than null
+            description: item
+                .description
+                .filter(|description| !description.is_empty()),
+            snapshot_id: item.snapshot_id,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaylistSnapshot {
+    snapshot_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreatedPlaylist {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaylistTrackId {
+    track: Option<TrackId>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TrackId {
+    id: Option<String>,
+    is_playable: Option<bool>,
+    restrictions: Option<Restrictions>,
+    linked_from: Option<LinkedFrom>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Restrictions {
+    reason: String,
+}
+
+// Set when Spotify relinked the track to a different one that is playable in the market
 #[derive(Debug, Deserialize)]
-struct PlaylistTrackObject {
-    track: TrackResponse,
+struct LinkedFrom {
+    id: Option<String>,
+}
+
+/// A track in a collection, with its availability in the user's market
+#[derive(Debug, Clone)]
+pub struct SourceTrack {
+    pub id: String,
+    pub is_playable: Option<bool>,
+    pub restriction: Option<String>,
+}
+
+impl TrackId {
+    // Keep the original id of relinked tracks, so songs don't change id between markets
+    fn into_source_track(self) -> Option<SourceTrack> {
+        let id = self.linked_from.and_then(|linked| linked.id).or(self.id)?;
+
+        Some(SourceTrack {
+            id,
+            is_playable: self.is_playable,
+            restriction: self.restrictions.map(|restrictions| restrictions.reason),
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct TracksResponse {
-    tracks: Vec<TrackResponse>,
+    // Unavailable or delisted ids come back as null
+    tracks: Vec<Option<TrackResponse>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -74,301 +236,1261 @@ struct TrackResponse {
     name: String,
     artists: Vec<Artist>,
     album: Album,
+    duration_ms: u32,
+    explicit: bool,
+    #[serde(default)]
+    popularity: u32,
+    uri: String,
+    #[serde(default)]
+    external_urls: ExternalUrls,
+    preview_url: Option<String>,
+    is_playable: Option<bool>,
+    restrictions: Option<Restrictions>,
+    linked_from: Option<LinkedFrom>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Album {
+    id: String,
+    name: String,
+    release_date: String,
     images: Vec<Image>,
 }
 
+impl From<TrackResponse> for Track {
+    fn from(track: TrackResponse) -> Self {
+        Self {
+            href: track.href,
+            // Keep the original id of relinked tracks, so it matches the stored song
+            id: track
+                .linked_from
+                .and_then(|linked| linked.id)
+                .unwrap_or(track.id),
+            name: track.name,
+            artists: track.artists,
+            image_url: track.album.images.first().map(|img| img.url.clone()),
+            album: AlbumSummary {
+                id: track.album.id,
+                name: track.album.name,
+                release_date: track.album.release_date,
+            },
+            images: track.album.images,
+            duration_ms: track.duration_ms,
+            explicit: track.explicit,
+            popularity: track.popularity,
+            uri: track.uri,
+            external_urls: track.external_urls,
+            preview_url: track.preview_url,
+            is_playable: track.is_playable,
+            restriction: track.restrictions.map(|restrictions| restrictions.reason),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Artist {
+    pub id: Option<String>,
     pub name: String,
     pub href: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FullArtist {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+    #[serde(default)]
+    pub popularity: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ArtistsResponse {
+    // Unknown ids come back as null
+    artists: Vec<Option<FullArtist>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AlbumId {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AlbumsResponse {
+    albums: Vec<AlbumTracks>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AlbumTracks {
+    id: String,
+    tracks: PaginatedResponse<TrackId>,
+}
+
+/// Where the songs of a ranking session come from
+///
+/// Every user ranks a source in a session of their own, so sources are identified by a key that
+/// ends with the id of the user who started it. The key is what gets stored in the `playlist_id`
+/// columns: `<id>:<user_id>` is a playlist, `album:<id>:<user_id>` and `artist:<id>:<user_id>`
+/// are an album or an artist's discography, `saved:<user_id>` is a user's Saved Tracks and
+/// `top:<time_range>:<user_id>` their top tracks. Keys without a user id are the caller's own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CollectionSource {
+    Playlist(String, i32),
+    SavedTracks(i32),
+    Album(String, i32),
+    Artist(String, i32),
+    TopTracks(String, i32),
+}
+
+impl CollectionSource {
+    /// Sources without a user id in the key belong to `user_id`
+    pub fn from_key(key: &str, user_id: i32) -> Result<Self, ApiError> {
+        let parts: Vec<&str> = key.split(':').collect();
+
+        let source = match parts.as_slice() {
+            ["saved"] => CollectionSource::SavedTracks(user_id),
+            ["saved", owner] => CollectionSource::SavedTracks(parse_user_id(owner)?),
+            ["top"] => CollectionSource::TopTracks("medium_term".to_string(), user_id),
+            ["top", range] => CollectionSource::TopTracks(parse_time_range(range)?, user_id),
+            ["top", range, owner] => {
+                CollectionSource::TopTracks(parse_time_range(range)?, parse_user_id(owner)?)
+            }
+            ["album", id] => CollectionSource::Album(id.to_string(), user_id),
+            ["album", id, owner] => CollectionSource::Album(id.to_string(), parse_user_id(owner)?),
+            ["artist", id] => CollectionSource::Artist(id.to_string(), user_id),
+            ["artist", id, owner] => {
+                CollectionSource::Artist(id.to_string(), parse_user_id(owner)?)
+            }
+            [id] => CollectionSource::Playlist(id.to_string(), user_id),
+            [id, owner] => CollectionSource::Playlist(id.to_string(), parse_user_id(owner)?),
+            _ => return Err(ApiError::BadRequest("Unknown collection source")),
+        };
+
+        Ok(source)
+    }
+
+    pub fn key(&self) -> String {
+        match self {
+            CollectionSource::Playlist(id, user_id) => format!("{}:{}", id, user_id),
+            CollectionSource::SavedTracks(user_id) => format!("saved:{}", user_id),
+            CollectionSource::Album(id, user_id) => format!("album:{}:{}", id, user_id),
+            CollectionSource::Artist(id, user_id) => format!("artist:{}:{}", id, user_id),
+            CollectionSource::TopTracks(range, user_id) => format!("top:{}:{}", range, user_id),
+        }
+    }
+
+    /// The user who started the ranking session, nobody else can start it
+    pub fn user_id(&self) -> i32 {
+        match self {
+            CollectionSource::Playlist(_, user_id)
+            | CollectionSource::SavedTracks(user_id)
+            | CollectionSource::Album(_, user_id)
+            | CollectionSource::Artist(_, user_id)
+            | CollectionSource::TopTracks(_, user_id) => *user_id,
+        }
+    }
+
+    /// The user a personal source belongs to, its songs can only be fetched with their account
+    pub fn owner_id(&self) -> Option<i32> {
+        match self {
+            CollectionSource::SavedTracks(user_id) | CollectionSource::TopTracks(_, user_id) => {
+                Some(*user_id)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse_user_id(user_id: &str) -> Result<i32, ApiError> {
+    user_id
+        .parse()
+        .map_err(|_| ApiError::BadRequest("Unknown collection source"))
+}
+
+fn parse_time_range(range: &str) -> Result<String, ApiError> {
+    match range {
+        "short_term" | "medium_term" | "long_term" => Ok(range.to_string()),
+        _ => Err(ApiError::BadRequest("Unknown collection source")),
+    }
+}
+
+// Error body returned by the Web API
+#[derive(Debug, Deserialize)]
+struct ApiErrorResponse {
+    error: ApiErrorObject,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiErrorObject {
+    message: String,
+}
+
+// Error body returned by the accounts service during the OAuth flow
+#[derive(Debug, Deserialize)]
+struct OauthErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
 pub enum SpotifyError {
+    #[error("Spotify access token is invalid or expired")]
     InvalidToken,
-    BadOauthRequest,
-    RateLimited,
-    Other(StatusCode),
+    #[error("Spotify OAuth request failed: {error}")]
+    BadOauthRequest {
+        error: String,
+        description: Option<String>,
+    },
+    #[error("Rate limited by Spotify")]
+    RateLimited { retry_after: Option<u64> },
+    #[error("Spotify API error ({status}): {message}")]
+    Api { status: StatusCode, message: String },
+    #[error("Failed to reach Spotify: {0}")]
+    Request(reqwest::Error),
+    #[error("Unexpected response from Spotify: {0}")]
+    Decode(reqwest::Error),
 }
 
-impl Spotify {
-    pub async fn from_response(
-        response: SpotifyResponse,
-        state: &AppState,
-    ) -> Result<Self, StatusCode> {
+// Responses that don't match what we expect aren't a connection problem, so they're kept apart
+impl From<reqwest::Error> for SpotifyError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            SpotifyError::Decode(err)
+        } else {
+            SpotifyError::Request(err)
+        }
+    }
+}
+
+impl SpotifyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            SpotifyError::InvalidToken => StatusCode::UNAUTHORIZED,
+            SpotifyError::BadOauthRequest { .. } => StatusCode::BAD_GATEWAY,
+            SpotifyError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            // Pass through errors that are caused by the request, anything else is Spotify's fault
+            SpotifyError::Api { status, .. } => match *status {
+                StatusCode::BAD_REQUEST | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => *status,
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            SpotifyError::Request(_) | SpotifyError::Decode(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            SpotifyError::InvalidToken => "spotify_invalid_token",
+            SpotifyError::BadOauthRequest { .. } => "spotify_oauth_error",
+            SpotifyError::RateLimited { .. } => "spotify_rate_limited",
+            SpotifyError::Api { .. } => "spotify_api_error",
+            SpotifyError::Request(_) => "spotify_unavailable",
+            SpotifyError::Decode(_) => "spotify_unexpected_response",
+        }
+    }
+
+    async fn from_api_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+
+        if status == StatusCode::UNAUTHORIZED {
+            return SpotifyError::InvalidToken;
+        }
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            return SpotifyError::RateLimited { retry_after };
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<ApiErrorResponse>(&body)
+            .map(|body| body.error.message)
+            .unwrap_or(body);
+
+        SpotifyError::Api { status, message }
+    }
+
+    async fn from_oauth_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+
+        match serde_json::from_str::<OauthErrorResponse>(&body) {
+            Ok(body) => SpotifyError::BadOauthRequest {
+                error: body.error,
+                description: body.error_description,
+            },
+            Err(_) => SpotifyError::Api {
+                status,
+                message: body,
+            },
+        }
+    }
+}
+
+/// Per-user locks so concurrent requests don't all refresh the same token
+#[derive(Clone, Default)]
+struct RefreshLocks(Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>);
+
+impl RefreshLocks {
+    async fn lock(&self, spotify_id: &str) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = self
+            .0
+            .lock()
+            .unwrap()
+            .entry(spotify_id.to_string())
+            .or_default()
+            .clone();
+
+        lock.lock_owned().await
+    }
+
+    // Drop the user's lock once nobody else is waiting on it
+    fn release(&self, spotify_id: &str) {
+        let mut locks = self.0.lock().unwrap();
+        if locks
+            .get(spotify_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(spotify_id);
+        }
+    }
+}
+
+// A page of a user's playlists along with the ETag Spotify returned for it
+#[derive(Clone)]
+struct CachedPage {
+    etag: Option<String>,
+    playlists: Vec<Playlist>,
+}
+
+#[derive(Clone)]
+struct CachedPlaylists {
+    fetched_at: Instant,
+    total: usize,
+    pages: Vec<CachedPage>,
+}
+
+/// Per-user cache of playlist listings, revalidated against Spotify with `If-None-Match`
+#[derive(Clone, Default)]
+struct PlaylistCache(Arc<Mutex<HashMap<String, CachedPlaylists>>>);
+
+impl PlaylistCache {
+    fn get(&self, spotify_id: &str) -> Option<CachedPlaylists> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(spotify_id)
+            .filter(|cached| cached.fetched_at.elapsed() < PLAYLIST_CACHE_MAX_AGE)
+            .cloned()
+    }
+
+    fn insert(&self, spotify_id: &str, playlists: CachedPlaylists) {
+        let mut cache = self.0.lock().unwrap();
+        cache.retain(|_, cached| cached.fetched_at.elapsed() < PLAYLIST_CACHE_MAX_AGE);
+
+        if cache.len() >= PLAYLIST_CACHE_MAX_USERS && !cache.contains_key(spotify_id) {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, cached)| cached.fetched_at)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+
+        cache.insert(spotify_id.to_string(), playlists);
+    }
+
+    fn remove(&self, spotify_id: &str) {
+        self.0.lock().unwrap().remove(spotify_id);
+    }
+}
+
+// Result of a conditional request, `None` body means Spotify answered 304 Not Modified
+struct Conditional<T> {
+    etag: Option<String>,
+    body: Option<T>,
+}
+
+/// Spotify API client that isn't tied to a request, so it can also be used from CLI tools and
+/// background jobs. Tokens are stored in the `users` table, hence the database pool.
+#[derive(Clone)]
+pub struct SpotifyClient {
+    pub http: reqwest::Client,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    pub pool: sqlx::PgPool,
+    /// Encrypts the tokens before they are stored
+    pub keyring: Keyring,
+    /// Maximum number of Spotify pages fetched at once
+    pub page_concurrency: usize,
+    /// How long before expiry an access token gets refreshed
+    pub refresh_margin: chrono::Duration,
+    /// How long a playlist listing is served from the cache without asking Spotify
+    pub playlist_cache_ttl: Duration,
+    refresh_locks: RefreshLocks,
+    playlist_cache: PlaylistCache,
+}
+
+impl SpotifyClient {
+    pub fn new(
+        pool: sqlx::PgPool,
+        keyring: Keyring,
+        client_id: String,
+        client_secret: String,
+        redirect_uri: String,
+    ) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            client_id,
+            client_secret,
+            redirect_uri,
+            pool,
+            keyring,
+            page_concurrency: 4,
+            refresh_margin: chrono::Duration::seconds(60),
+            playlist_cache_ttl: Duration::from_secs(60),
+            refresh_locks: RefreshLocks::default(),
+            playlist_cache: PlaylistCache::default(),
+        }
+    }
+
+    // Start an authorization code flow with PKCE, the returned state has to be checked in the callback
+    pub fn authorize_url(&self, scopes: &[&str]) -> (Url, CsrfToken, PkceCodeVerifier) {
+        let client = BasicClient::new(ClientId::new(self.client_id.clone()))
+            .set_auth_uri(
+                AuthUrl::new("https://accounts.spotify.com/authorize".to_string())
+                    .expect("Spotify URLs are valid"),
+            )
+            .set_redirect_uri(
+                RedirectUrl::new(self.redirect_uri.clone())
+                    .expect("SPOTIFY_REDIRECT_URI is a valid URL"),
+            );
+
+        let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+        let (url, state) = client
+            .authorize_url(CsrfToken::new_random)
+            .add_scopes(scopes.iter().map(|scope| Scope::new(scope.to_string())))
+            .set_pkce_challenge(challenge)
+            .url();
+
+        (url, state, verifier)
+    }
+
+    // Drop anything kept in memory for a user, e.g. after their account is deleted or Spotify
+    // revoked their tokens
+    pub fn forget_user(&self, spotify_id: &str) {
+        self.playlist_cache.remove(spotify_id);
+    }
+
+    // Exchange a code or refresh token for an access token
+    pub async fn request_token(
+        &self,
+        form: &[(&str, &str)],
+    ) -> Result<SpotifyResponse, SpotifyError> {
+        let response = self
+            .http
+            .post("https://accounts.spotify.com/api/token")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(form)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(SpotifyError::from_oauth_response(response).await);
+        }
+
+        Ok(response.json().await?)
+    }
+
+    // Store the tokens from a completed OAuth flow, creating the user if they are new
+    pub async fn login(&self, response: SpotifyResponse) -> Result<Spotify, ApiError> {
         let expires_at = Utc::now() + chrono::Duration::seconds(response.expires_in as i64);
 
         // Get id
-        let spotify_id = state
-            .client
+        let profile = self
+            .http
             .get("https://api.spotify.com/v1/me")
             .bearer_auth(&response.access_token)
             .send()
             .await
-            .map_err(|err| {
-                tracing::error!("Failed to fetch Spotify profile: {}", err);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?
+            .map_err(SpotifyError::from)?;
+
+        if !profile.status().is_success() {
+            return Err(SpotifyError::from_api_response(profile).await.into());
+        }
+
+        let profile = profile
             .json::<SpotifyProfile>()
             .await
-            .map_err(|err| {
-                tracing::error!("Failed to parse Spotify profile: {}", err);
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?
-            .id;
+            .map_err(SpotifyError::from)?;
+        let spotify_id = profile.id.clone();
+        // Always included when exchanging a code, only refreshes may leave it out
+        let refresh_token = response.refresh_token.ok_or(SpotifyError::Api {
+            status: StatusCode::BAD_GATEWAY,
+            message: "No refresh token in the token response".to_string(),
+        })?;
+        let sealed = self
+            .keyring
+            .seal(&spotify_id, &response.access_token, &refresh_token);
 
         // Insert into database, if id already exists, update the tokens
         sqlx::query!(
-            "INSERT INTO users (spotify_id, access_token, refresh_token, expires_at) VALUES ($1, $2, $3, $4)
+            "INSERT INTO users (spotify_id, access_token, refresh_token, data_key, key_id, tokens_bound, expires_at, country, display_name, avatar_url, product, profile_updated_at) VALUES ($1, $2, $3, $4, $5, TRUE, $6, $7, $8, $9, $10, NOW())
              ON CONFLICT (spotify_id)
-             DO UPDATE SET access_token = EXCLUDED.access_token, refresh_token = EXCLUDED.refresh_token, expires_at = EXCLUDED.expires_at",
+             DO UPDATE SET access_token = EXCLUDED.access_token, refresh_token = EXCLUDED.refresh_token, data_key = EXCLUDED.data_key, key_id = EXCLUDED.key_id, tokens_bound = TRUE, expires_at = EXCLUDED.expires_at,
+                country = EXCLUDED.country, display_name = EXCLUDED.display_name, avatar_url = EXCLUDED.avatar_url, product = EXCLUDED.product, profile_updated_at = EXCLUDED.profile_updated_at, needs_reauth = FALSE",
             spotify_id,
-            response.access_token,
-            response.refresh_token,
-            expires_at
+            sealed.access_token,
+            sealed.refresh_token,
+            sealed.data_key,
+            sealed.key_id,
+            expires_at,
+            profile.country,
+            profile.display_name,
+            profile.avatar_url(),
+            profile.product
         )
-        .execute(&state.pool)
-        .await
-        .map_err(|err| {
-            tracing::error!("Failed to insert/update user: {}", err);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .execute(&self.pool)
+        .await?;
 
-        Ok(Self {
+        Ok(Spotify {
+            client: self.clone(),
+            market: profile.country,
             access_token: response.access_token,
-            refresh_token: response.refresh_token.unwrap(),
+            refresh_token,
             expires_at,
             spotify_id,
         })
     }
 
-    pub fn is_expired(&self) -> bool {
-        Utc::now() >= self.expires_at
+    // Load the tokens of a user, e.g. one resolved from a session
+    pub async fn for_user(&self, user_id: i32) -> Result<Spotify, ApiError> {
+        let user = sqlx::query!(
+            "SELECT spotify_id, access_token, refresh_token, data_key, key_id, expires_at, country FROM users WHERE id = $1",
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        let (access_token, refresh_token) = self.keyring.open(
+            &user.spotify_id,
+            user.key_id.as_deref(),
+            user.data_key.as_deref(),
+            &user.access_token,
+            &user.refresh_token,
+        )?;
+
+        Ok(Spotify {
+            client: self.clone(),
+            market: user.country,
+            access_token,
+            refresh_token,
+            expires_at: user.expires_at,
+            spotify_id: user.spotify_id,
+        })
+    }
+}
+
+impl Spotify {
+    // True if the token expires within `margin`, so it can be refreshed before a request fails
+    pub fn expires_within(&self, margin: chrono::Duration) -> bool {
+        Utc::now() + margin >= self.expires_at
+    }
+
+    pub async fn refresh(&mut self) -> Result<(), ApiError> {
+        if !self.expires_within(self.client.refresh_margin) {
+            return Ok(());
+        }
+
+        self.force_refresh().await
+    }
+
+    // Refresh the token regardless of its expiry, e.g. after Spotify rejected it
+    pub async fn force_refresh(&mut self) -> Result<(), ApiError> {
+        let guard = self.client.refresh_locks.lock(&self.spotify_id).await;
+        let result = self.refresh_locked().await;
+        drop(guard);
+        self.client.refresh_locks.release(&self.spotify_id);
+
+        result
     }
 
-    pub async fn refresh(&mut self, state: &AppState) -> Result<(), StatusCode> {
-        if !self.is_expired() {
+    // Must only be called while holding this user's refresh lock
+    async fn refresh_locked(&mut self) -> Result<(), ApiError> {
+        // Another request may have refreshed the token while we were waiting for the lock
+        let current = sqlx::query!(
+            "SELECT access_token, refresh_token, data_key, key_id, expires_at FROM users WHERE spotify_id = $1",
+            self.spotify_id
+        )
+        .fetch_one(&self.client.pool)
+        .await?;
+
+        let (access_token, refresh_token) = self.client.keyring.open(
+            &self.spotify_id,
+            current.key_id.as_deref(),
+            current.data_key.as_deref(),
+            &current.access_token,
+            &current.refresh_token,
+        )?;
+
+        if access_token != self.access_token
+            && Utc::now() + self.client.refresh_margin < current.expires_at
+        {
+            self.access_token = access_token;
+            self.refresh_token = refresh_token;
+            self.expires_at = current.expires_at;
             return Ok(());
         }
 
-        let response: SpotifyResponse = state
+        // Spotify may have rotated the refresh token since this session was loaded
+        self.refresh_token = refresh_token;
+
+        let response = match self
             .client
-            .post("https://accounts.spotify.com/api/token")
-            .header("Content-Type", "application/x-www-form-urlencoded")
-            .basic_auth(&state.client_id, Some(&state.client_secret))
-            .form(&[
+            .request_token(&[
                 ("grant_type", "refresh_token"),
                 ("refresh_token", &self.refresh_token),
             ])
-            .send()
             .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-            .json()
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        self.access_token = response.access_token.clone();
-        let refresh_token_to_store = if let Some(refresh_token) = response.refresh_token {
-            self.refresh_token = refresh_token.clone();
-            Some(refresh_token)
-        } else {
-            Some(self.refresh_token.clone())
+        {
+            Ok(response) => response,
+            Err(SpotifyError::BadOauthRequest { error, .. }) if error == "invalid_grant" => {
+                return Err(self.revoke().await);
+            }
+            Err(err) => return Err(err.into()),
         };
+
+        self.access_token = response.access_token;
+        if let Some(refresh_token) = response.refresh_token {
+            self.refresh_token = refresh_token;
+        }
         self.expires_at = Utc::now() + chrono::Duration::seconds(response.expires_in as i64);
 
+        let sealed =
+            self.client
+                .keyring
+                .seal(&self.spotify_id, &self.access_token, &self.refresh_token);
+
         sqlx::query!(
-            "UPDATE users SET access_token = $1, refresh_token = $2, expires_at = NOW() + INTERVAL '1 second' * $3 WHERE spotify_id = $4",
-            response.access_token,
-            refresh_token_to_store,
+            "UPDATE users SET access_token = $1, refresh_token = $2, data_key = $3, key_id = $4, tokens_bound = TRUE, expires_at = NOW() + INTERVAL '1 second' * $5 WHERE spotify_id = $6",
+            sealed.access_token,
+            sealed.refresh_token,
+            sealed.data_key,
+            sealed.key_id,
             response.expires_in as i64,
             self.spotify_id
         )
-        .execute(&state.pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .execute(&self.client.pool)
+        .await?;
 
         Ok(())
     }
 
-    // GET function with token refresh
-    async fn get<T: serde::de::DeserializeOwned>(
+    // The refresh token has been revoked, so the user has to go through the login flow again
+    async fn revoke(&self) -> ApiError {
+        tracing::warn!(
+            "Refresh token for {} was revoked, invalidating sessions",
+            self.spotify_id
+        );
+
+        let result = sqlx::query!(
+            "WITH revoked AS (
+                UPDATE users SET needs_reauth = TRUE WHERE spotify_id = $1 RETURNING id
+             )
+             DELETE FROM sessions WHERE user_id IN (SELECT id FROM revoked)",
+            self.spotify_id
+        )
+        .execute(&self.client.pool)
+        .await;
+
+        self.client.forget_user(&self.spotify_id);
+
+        match result {
+            Ok(_) => ApiError::ReauthRequired,
+            Err(err) => err.into(),
+        }
+    }
+
+    // Re-fetch the profile fields that are cached in `users`
+    pub async fn update_profile(&mut self) -> Result<(), ApiError> {
+        let profile: SpotifyProfile = self.get("https://api.spotify.com/v1/me").await?;
+
+        sqlx::query!(
+            "UPDATE users SET display_name = $1, avatar_url = $2, country = $3, product = $4, profile_updated_at = NOW() WHERE spotify_id = $5",
+            profile.display_name,
+            profile.avatar_url(),
+            profile.country,
+            profile.product,
+            self.spotify_id
+        )
+        .execute(&self.client.pool)
+        .await?;
+
+        self.market = profile.country;
+
+        Ok(())
+    }
+
+    // Restrict a track query to the user's market, so availability and relinking are included
+    fn with_market(&self, url: &str) -> String {
+        match &self.market {
+            Some(market) => {
+                let mut url = reqwest::Url::parse(url).expect("Spotify URLs are valid");
+                url.query_pairs_mut().append_pair("market", market);
+                url.to_string()
+            }
+            None => url.to_string(),
+        }
+    }
+
+    // GET function with token refresh, retried once if Spotify rejects the token
+    async fn get<T: serde::de::DeserializeOwned>(&mut self, url: &str) -> Result<T, ApiError> {
+        self.request(Method::GET, url, None::<&()>).await
+    }
+
+    // Any request with token refresh, retried once if Spotify rejects the token
+    async fn request<B: Serialize + ?Sized, T: serde::de::DeserializeOwned>(
         &mut self,
-        state: &AppState,
+        method: Method,
         url: &str,
-    ) -> Result<T, SpotifyError> {
-        if self.is_expired() {
-            self.refresh(state).await.map_err(|e| {
-                tracing::error!("Failed to refresh Spotify token: {:#?}", e);
-                SpotifyError::BadOauthRequest
-            })?;
+        body: Option<&B>,
+    ) -> Result<T, ApiError> {
+        self.refresh().await?;
+
+        let result = match self.send(method.clone(), url, body).await {
+            Err(ApiError::SpotifyError(SpotifyError::InvalidToken)) => {
+                tracing::info!("Spotify rejected the access token, refreshing and retrying");
+                self.force_refresh().await?;
+                self.send(method.clone(), url, body).await
+            }
+            result => result,
+        };
+
+        // Writes can change the user's playlists, e.g. an export creates one
+        if method != Method::GET {
+            self.client.playlist_cache.remove(&self.spotify_id);
         }
 
-        let response = state
+        result
+    }
+
+    // GET function without token refresh, so it can be run concurrently
+    async fn fetch<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, ApiError> {
+        self.send(Method::GET, url, None::<&()>).await
+    }
+
+    async fn send<B: Serialize + ?Sized, T: serde::de::DeserializeOwned>(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&B>,
+    ) -> Result<T, ApiError> {
+        let mut request = self
             .client
-            .get(url)
-            .bearer_auth(&self.access_token)
-            .send()
-            .await
-            .map_err(|e| {
-                tracing::error!("Failed to send Spotify API request: {:#?}", e);
-                SpotifyError::Other(StatusCode::INTERNAL_SERVER_ERROR)
-            })?
-            .error_for_status()
-            .map_err(|e| {
-                tracing::error!("Spotify API request failed: {:#?}", e);
-                if e.status() == Some(StatusCode::UNAUTHORIZED) {
-                    SpotifyError::InvalidToken
-                } else if e.status() == Some(StatusCode::TOO_MANY_REQUESTS) {
-                    SpotifyError::RateLimited
-                } else {
-                    SpotifyError::Other(e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
-                }
-            })?;
+            .http
+            .request(method, url)
+            .bearer_auth(&self.access_token);
+
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+
+        let response = request.send().await.map_err(SpotifyError::from)?;
+
+        if !response.status().is_success() {
+            let err = SpotifyError::from_api_response(response).await;
+            tracing::error!("Spotify API request({}) failed: {}", url, err);
+            return Err(err.into());
+        }
 
         let response = response.json::<T>().await.map_err(|e| {
             tracing::error!("Failed to parse Spotify API response({:#?}): {:#?}", url, e);
-            SpotifyError::Other(StatusCode::INTERNAL_SERVER_ERROR)
+            SpotifyError::from(e)
         })?;
 
         Ok(response)
     }
 
-    pub async fn get_playlists(&mut self, state: &AppState) -> Result<Vec<Playlist>, SpotifyError> {
-        let mut playlists = Vec::new();
-        let mut next_url = Some("https://api.spotify.com/v1/me/playlists".to_string());
-        while let Some(url) = next_url {
-            let response: PaginatedResponse<PlaylistResponse> = self.get(state, &url).await?;
+    // Conditional GET with token refresh, retried once if Spotify rejects the token
+    async fn get_conditional<T: serde::de::DeserializeOwned>(
+        &mut self,
+        url: &str,
+        etag: Option<&str>,
+    ) -> Result<Conditional<T>, ApiError> {
+        self.refresh().await?;
 
-            playlists.extend(response.items.into_iter().map(|item| {
-                Playlist {
-                    href: item.href,
-                    id: item.id,
-                    name: item.name,
-                    image_url: item
-                        .images
-                        .into_iter()
-                        .next()
-                        .map(|img| img.url)
-                        .unwrap_or_default(),
-                }
-            }));
-            next_url = response.next;
+        match self.fetch_conditional(url, etag).await {
+            Err(ApiError::SpotifyError(SpotifyError::InvalidToken)) => {
+                tracing::info!("Spotify rejected the access token, refreshing and retrying");
+                self.force_refresh().await?;
+                self.fetch_conditional(url, etag).await
+            }
+            result => result,
+        }
+    }
+
+    // GET function that sends the cached ETag, so unchanged resources aren't downloaded again
+    // Like `fetch` it doesn't refresh the token, so it can be run concurrently
+    async fn fetch_conditional<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        etag: Option<&str>,
+    ) -> Result<Conditional<T>, ApiError> {
+        let mut request = self.client.http.get(url).bearer_auth(&self.access_token);
+
+        if let Some(etag) = etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
         }
+
+        let response = request.send().await.map_err(SpotifyError::from)?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Conditional {
+                etag: etag.map(str::to_string),
+                body: None,
+            });
+        }
+
+        if !response.status().is_success() {
+            let err = SpotifyError::from_api_response(response).await;
+            tracing::error!("Spotify API request({}) failed: {}", url, err);
+            return Err(err.into());
+        }
+
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let body = response.json::<T>().await.map_err(SpotifyError::from)?;
+
+        Ok(Conditional {
+            etag,
+            body: Some(body),
+        })
+    }
+
+    // Fetches the first page, then uses its total to fetch the rest of the pages concurrently
+    async fn get_all_pages<T: serde::de::DeserializeOwned>(
+        &mut self,
+        url: &str,
+        limit: usize,
+    ) -> Result<Vec<T>, ApiError> {
+        let page_url = |offset: usize| {
+            let mut url = reqwest::Url::parse(url).expect("Spotify URLs are valid");
+            url.query_pairs_mut()
+                .append_pair("limit", &limit.to_string())
+                .append_pair("offset", &offset.to_string());
+            url.to_string()
+        };
+
+        let first: PaginatedResponse<T> = self.get(&page_url(0)).await?;
+        let mut items = first.items;
+
+        let this = &*self;
+        let pages: Vec<PaginatedResponse<T>> = stream::iter((limit..first.total).step_by(limit))
+            .map(|offset| {
+                let url = page_url(offset);
+                async move { this.fetch(&url).await }
+            })
+            .buffered(self.client.page_concurrency.max(1))
+            .try_collect()
+            .await?;
+
+        for page in pages {
+            items.extend(page.items);
+        }
+
+        Ok(items)
+    }
+
+    // Served from the cache while it is fresh, otherwise every page is revalidated with its ETag
+    // so only pages that changed are downloaded again. `refresh` skips the fresh cache check.
+    pub async fn get_playlists(&mut self, refresh: bool) -> Result<Vec<Playlist>, ApiError> {
+        let cached = self.client.playlist_cache.get(&self.spotify_id);
+
+        if !refresh
+            && let Some(cached) = &cached
+            && cached.fetched_at.elapsed() < self.client.playlist_cache_ttl
+        {
+            return Ok(cached
+                .pages
+                .iter()
+                .flat_map(|page| page.playlists.clone())
+                .collect());
+        }
+
+        let cached_page = |idx: usize| cached.as_ref().and_then(|cached| cached.pages.get(idx));
+        let page_url = |offset: usize| {
+            format!(
+                "https://api.spotify.com/v1/me/playlists?limit={}&offset={}",
+                PLAYLISTS_PAGE_LIMIT, offset
+            )
+        };
+
+        // The first page refreshes the token if needed, the rest reuse it
+        let first: Conditional<PaginatedResponse<PlaylistResponse>> = self
+            .get_conditional(
+                &page_url(0),
+                cached_page(0).and_then(|page| page.etag.as_deref()),
+            )
+            .await?;
+        let total = match (&first.body, &cached) {
+            (Some(body), _) => body.total,
+            (None, Some(cached)) => cached.total,
+            (None, None) => 0,
+        };
+
+        let this = &*self;
+        let rest: Vec<Conditional<PaginatedResponse<PlaylistResponse>>> = stream::iter(
+            (PLAYLISTS_PAGE_LIMIT..total)
+                .step_by(PLAYLISTS_PAGE_LIMIT)
+                .enumerate(),
+        )
+        .map(|(idx, offset)| {
+            let url = page_url(offset);
+            let etag = cached_page(idx + 1).and_then(|page| page.etag.clone());
+            async move { this.fetch_conditional(&url, etag.as_deref()).await }
+        })
+        .buffered(self.client.page_concurrency.max(1))
+        .try_collect()
+        .await?;
+
+        let pages: Vec<CachedPage> = std::iter::once(first)
+            .chain(rest)
+            .enumerate()
+            .map(|(idx, page)| match page.body {
+                Some(body) => CachedPage {
+                    etag: page.etag,
+                    playlists: body.items.into_iter().map(Playlist::from).collect(),
+                },
+                None => cached_page(idx).cloned().unwrap_or(CachedPage {
+                    etag: None,
+                    playlists: Vec::new(),
+                }),
+            })
+            .collect();
+
+        let playlists = pages
+            .iter()
+            .flat_map(|page| page.playlists.clone())
+            .collect();
+
+        self.client.playlist_cache.insert(
+            &self.spotify_id,
+            CachedPlaylists {
+                fetched_at: Instant::now(),
+                total,
+                pages,
+            },
+        );
+
         Ok(playlists)
     }
 
-    pub async fn get_playlist_tracks(
+    pub async fn get_playlist_snapshot_id(
         &mut self,
-        state: &AppState,
         playlist_id: &str,
-    ) -> Result<Vec<Track>, SpotifyError> {
-        let mut tracks = Vec::new();
-        let mut next_url = Some(format!(
-            "https://api.spotify.com/v1/playlists/{}/tracks",
+    ) -> Result<String, ApiError> {
+        let url = format!(
+            "https://api.spotify.com/v1/playlists/{}?fields=snapshot_id",
+            playlist_id
+        );
+
+        let response: PlaylistSnapshot = self.get(&url).await?;
+        Ok(response.snapshot_id)
+    }
+
+    // Every item in playlist order, `None` for local files and removed tracks
+    pub async fn get_playlist_items(
+        &mut self,
+        playlist_id: &str,
+    ) -> Result<Vec<Option<SourceTrack>>, ApiError> {
+        let url = self.with_market(&format!(
+            "https://api.spotify.com/v1/playlists/{}/tracks?fields=total,items(track(id,is_playable,restrictions,linked_from(id)))",
             playlist_id
         ));
-        while let Some(url) = next_url {
-            let response: PaginatedResponse<PlaylistTrackObject> = self.get(state, &url).await?;
-
-            tracks.extend(response.items.into_iter().map(|item| {
-                Track {
-                    href: item.track.href,
-                    id: item.track.id,
-                    name: item.track.name,
-                    artists: item.track.artists,
-                    image_url: Some(
-                        item.track
-                            .album
-                            .images
-                            .into_iter()
-                            .next()
-                            .map_or_else(String::new, |img| img.url),
-                    ),
-                }
-            }));
-            next_url = response.next;
+        let items: Vec<PlaylistTrackId> = self.get_all_pages(&url, TRACKS_PAGE_LIMIT).await?;
+
+        Ok(items
+            .into_iter()
+            .map(|item| item.track.and_then(TrackId::into_source_track))
+            .collect())
+    }
+
+    // Only requests the track ids, which is a lot cheaper than the full track objects
+    pub async fn get_playlist_track_ids(
+        &mut self,
+        playlist_id: &str,
+    ) -> Result<Vec<SourceTrack>, ApiError> {
+        // Local files and removed tracks have no id, so they can't be ranked
+        Ok(self
+            .get_playlist_items(playlist_id)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
+    pub async fn create_playlist(
+        &mut self,
+        name: &str,
+        description: &str,
+        public: bool,
+    ) -> Result<String, ApiError> {
+        let url = format!(
+            "https://api.spotify.com/v1/users/{}/playlists",
+            self.spotify_id
+        );
+        let body = serde_json::json!({
+            "name": name,
+            "description": description,
+            "public": public,
+        });
+
+        let response: CreatedPlaylist = self.request(Method::POST, &url, Some(&body)).await?;
+        Ok(response.id)
+    }
+
+    // Replace every item in a playlist, returns the new snapshot id
+    pub async fn replace_playlist_tracks(
+        &mut self,
+        playlist_id: &str,
+        uris: &[String],
+    ) -> Result<String, ApiError> {
+        let url = format!(
+            "https://api.spotify.com/v1/playlists/{}/tracks",
+            playlist_id
+        );
+
+        // Spotify only accepts 100 items per request, so replace with the first batch and
+        // append the rest
+        let mut batches = uris.chunks(PLAYLIST_ITEMS_BATCH);
+        let first = batches.next().unwrap_or_default();
+        let mut response: PlaylistSnapshot = self
+            .request(
+                Method::PUT,
+                &url,
+                Some(&serde_json::json!({ "uris": first })),
+            )
+            .await?;
+
+        for batch in batches {
+            response = self
+                .request(
+                    Method::POST,
+                    &url,
+                    Some(&serde_json::json!({ "uris": batch })),
+                )
+                .await?;
         }
-        Ok(tracks)
+
+        Ok(response.snapshot_id)
+    }
+
+    // Move a single item, guarded by the snapshot the move was computed against
+    pub async fn reorder_playlist_track(
+        &mut self,
+        playlist_id: &str,
+        range_start: usize,
+        insert_before: usize,
+        snapshot_id: &str,
+    ) -> Result<String, ApiError> {
+        let url = format!(
+            "https://api.spotify.com/v1/playlists/{}/tracks",
+            playlist_id
+        );
+        let body = serde_json::json!({
+            "range_start": range_start,
+            "insert_before": insert_before,
+            "range_length": 1,
+            "snapshot_id": snapshot_id,
+        });
+
+        let response: PlaylistSnapshot = self.request(Method::PUT, &url, Some(&body)).await?;
+        Ok(response.snapshot_id)
     }
 
-    pub async fn get_tracks(
+    // Only playlists have a snapshot id, every other source has to be diffed on each sync
+    pub async fn get_source_snapshot_id(
         &mut self,
-        state: &AppState,
-        track_ids: &[String],
-    ) -> Result<Vec<Track>, SpotifyError> {
-        if track_ids.is_empty() {
-            return Ok(Vec::new());
+        source: &CollectionSource,
+    ) -> Result<Option<String>, ApiError> {
+        match source {
+            CollectionSource::Playlist(id, _) => Ok(Some(self.get_playlist_snapshot_id(id).await?)),
+            _ => Ok(None),
         }
+    }
+
+    pub async fn get_source_tracks(
+        &mut self,
+        source: &CollectionSource,
+    ) -> Result<Vec<SourceTrack>, ApiError> {
+        let (url, limit) = match source {
+            CollectionSource::Playlist(id, _) => return self.get_playlist_track_ids(id).await,
+            CollectionSource::Artist(id, _) => return self.get_artist_tracks(id).await,
+            CollectionSource::SavedTracks(_) => {
+                let url = self.with_market("https://api.spotify.com/v1/me/tracks");
+                let items: Vec<PlaylistTrackId> = self.get_all_pages(&url, 50).await?;
 
-        let ids = track_ids.join(",");
-        let url = format!("https://api.spotify.com/v1/tracks?ids={}", ids);
+                return Ok(items
+                    .into_iter()
+                    .filter_map(|item| item.track.and_then(TrackId::into_source_track))
+                    .collect());
+            }
+            CollectionSource::Album(id, _) => (
+                format!("https://api.spotify.com/v1/albums/{}/tracks", id),
+                50,
+            ),
+            CollectionSource::TopTracks(range, _) => (
+                format!(
+                    "https://api.spotify.com/v1/me/top/tracks?time_range={}",
+                    range
+                ),
+                50,
+            ),
+        };
 
-        let response: TracksResponse = self.get(state, &url).await?;
+        let url = self.with_market(&url);
+        let items: Vec<TrackId> = self.get_all_pages(&url, limit).await?;
 
-        Ok(response
-            .tracks
+        Ok(items
             .into_iter()
-            .map(|track| Track {
-                href: track.href,
-                id: track.id,
-                name: track.name,
-                artists: track.artists,
-                image_url: Some(
-                    track
-                        .album
-                        .images
-                        .into_iter()
-                        .next()
-                        .map_or_else(String::new, |img| img.url),
-                ),
-            })
+            .filter_map(TrackId::into_source_track)
             .collect())
     }
-}
 
-impl<S> FromRequestParts<S> for Spotify
-where
-    S: Send + Sync,
-    AppState: axum::extract::FromRef<S>,
-{
-    type Rejection = (StatusCode, &'static str);
+    // Every track from the artist's albums and singles, compilations and features are skipped
+    async fn get_artist_tracks(&mut self, artist_id: &str) -> Result<Vec<SourceTrack>, ApiError> {
+        let url = self.with_market(&format!(
+            "https://api.spotify.com/v1/artists/{}/albums?include_groups=album,single",
+            artist_id
+        ));
+        let albums: Vec<AlbumId> = self.get_all_pages(&url, 50).await?;
 
-    async fn from_request_parts(
-        parts: &mut axum::http::request::Parts,
-        state: &S,
-    ) -> Result<Self, Self::Rejection> {
-        let State(app_state): State<AppState> =
-            State::from_request_parts(parts, state).await.unwrap();
+        let mut tracks = Vec::new();
 
-        let jar = CookieJar::from_request_parts(parts, state)
-            .await
-            .map_err(|_| (StatusCode::BAD_REQUEST, "Failed to extract session cookie"))?;
-
-        let session_token = jar
-            .get("session_token")
-            .ok_or((StatusCode::BAD_REQUEST, "Session token not found"))?
-            .value();
-
-        sqlx::query_as!(
-            Spotify,
-            "SELECT u.spotify_id, u.access_token, u.refresh_token, u.expires_at
-             FROM users u
-             JOIN sessions s ON u.id = s.user_id
-             WHERE s.token = $1",
-            session_token
-        )
-        .fetch_one(&app_state.pool)
-        .await
-        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid token"))
+        // The several albums endpoint takes at most 20 ids, and includes the first page of tracks
+        for chunk in albums.chunks(20) {
+            let ids: Vec<&str> = chunk.iter().map(|album| album.id.as_str()).collect();
+            let url = self.with_market(&format!(
+                "https://api.spotify.com/v1/albums?ids={}",
+                ids.join(",")
+            ));
+            let response: AlbumsResponse = self.get(&url).await?;
+
+            for album in response.albums {
+                let mut album_tracks = album.tracks.items;
+
+                if album.tracks.total > album_tracks.len() {
+                    let url = self.with_market(&format!(
+                        "https://api.spotify.com/v1/albums/{}/tracks",
+                        album.id
+                    ));
+                    album_tracks = self.get_all_pages(&url, 50).await?;
+                }
+
+                tracks.extend(
+                    album_tracks
+                        .into_iter()
+                        .filter_map(TrackId::into_source_track),
+                );
+            }
+        }
+
+        // Guard against the same track showing up on more than one release
+        let mut seen = std::collections::HashSet::new();
+        tracks.retain(|track| seen.insert(track.id.clone()));
+
+        Ok(tracks)
+    }
+
+    pub async fn get_tracks(&mut self, track_ids: &[String]) -> Result<Vec<Track>, ApiError> {
+        let mut tracks = Vec::with_capacity(track_ids.len());
+
+        // The several tracks endpoint takes at most 50 ids
+        for chunk in track_ids.chunks(50) {
+            let url = self.with_market(&format!(
+                "https://api.spotify.com/v1/tracks?ids={}",
+                chunk.join(",")
+            ));
+            let response: TracksResponse = self.get(&url).await?;
+            tracks.extend(response.tracks.into_iter().flatten().map(Track::from));
+        }
+
+        Ok(tracks)
+    }
+
+    pub async fn get_artists(
+        &mut self,
+        artist_ids: &[String],
+    ) -> Result<Vec<FullArtist>, ApiError> {
+        let mut artists = Vec::with_capacity(artist_ids.len());
+
+        // The several artists endpoint takes at most 50 ids
+        for chunk in artist_ids.chunks(50) {
+            let url = format!("https://api.spotify.com/v1/artists?ids={}", chunk.join(","));
+            let response: ArtistsResponse = self.get(&url).await?;
+            artists.extend(response.artists.into_iter().flatten());
+        }
+
+        Ok(artists)
     }
 }
 
 #[derive(Debug, Deserialize)]
 struct PaginatedResponse<T> {
     items: Vec<T>,
-    next: Option<String>,
+    #[serde(default)]
+    total: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_without_user_are_the_callers() {
+        for (key, expected) in [
+            ("abc", "abc:7"),
+            ("album:abc", "album:abc:7"),
+            ("artist:abc", "artist:abc:7"),
+            ("saved", "saved:7"),
+            ("top", "top:medium_term:7"),
+            ("top:short_term", "top:short_term:7"),
+        ] {
+            let source = CollectionSource::from_key(key, 7).unwrap();
+            assert_eq!(source.key(), expected);
+            assert_eq!(source.user_id(), 7);
+        }
+    }
+
+    #[test]
+    fn keys_with_user_are_theirs() {
+        for key in [
+            "abc:3",
+            "album:abc:3",
+            "artist:abc:3",
+            "saved:3",
+            "top:long_term:3",
+        ] {
+            let source = CollectionSource::from_key(key, 7).unwrap();
+            assert_eq!(source.key(), key);
+            assert_eq!(source.user_id(), 3);
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        for key in ["abc:def", "album:abc:def", "top:forever", "a:b:c:d"] {
+            assert!(CollectionSource::from_key(key, 7).is_err(), "{key}");
+        }
+    }
 }
@@ -1,13 +1,12 @@
 import { redirect } from "@sveltejs/kit";
 
-export async function load({ fetch }) {
-    // Fetch the redirect url from the backend /login endpoint
-    const res = await fetch("http://localhost:3000/login", {
-        redirect: "manual",
-    });
-
-    if (res.status === 303) {
-        const location = res.headers.get("location") ?? res.url;
-        throw redirect(303, location);
+export function load({ url }) {
+    // Send the browser to the backend itself, it sets the cookie the callback checks the state against
+    const login = new URL("http://localhost:3000/login");
+    const returnTo = url.searchParams.get("return_to");
+    if (returnTo) {
+        login.searchParams.set("return_to", returnTo);
     }
-}
\ No newline at end of file
+
+    throw redirect(303, login.toString());
+}